@@ -0,0 +1,219 @@
+//! Command argument files - the arguments of each task of a job array, one line per task.
+
+use anyhow::{Context, anyhow};
+
+use crate::catalog::Image;
+
+/// Environment variables each container relies on, which no column may replace
+const RESERVED_NAMES: [&str; 4] = ["HOME", "PATH", "HPC_HOME", "FS_LICENSE"];
+
+/// Separates the columns of a command argument file
+#[derive(Clone, Copy, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Delimiter {
+    Tab,
+    Comma,
+}
+
+impl Delimiter {
+    pub fn byte(self) -> u8 {
+        match self {
+            Delimiter::Tab => b'\t',
+            Delimiter::Comma => b',',
+        }
+    }
+}
+
+pub struct CommandArgFile {
+    /// Column names, one per argument in each row
+    pub header: Option<Vec<String>>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl CommandArgFile {
+    /// Fails when a column would replace an environment variable that `image` sets in each
+    /// container, e.g. FS_LICENSE of freesurfer.
+    pub fn check_image_env(&self, image: &Image) -> anyhow::Result<()> {
+        let (Some(header), Image::Known(image)) = (&self.header, image) else {
+            return Ok(());
+        };
+        match header.iter().find(|name| image.env.contains_key(*name)) {
+            Some(name) => Err(anyhow!(
+                "Header \"{name}\" would replace the environment variable of the same name that {} \
+                 sets - rename the column",
+                image.name
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reads and parses the command argument file at `path`.
+pub fn read_command_arg_file(
+    path: &std::path::Path,
+    delimiter: Option<Delimiter>,
+    has_header: bool,
+) -> anyhow::Result<CommandArgFile> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("Unable to read command argument file, {path:?}"))?;
+    parse_command_arg_file(&contents, delimiter, has_header)
+        .with_context(|| format!("Unable to parse command argument file, {path:?}"))
+}
+
+/// Parses the nonempty, trimmed lines of a command argument file into rows of arguments. Without a
+/// delimiter each row holds the whole line. With a delimiter each row holds one argument per column
+/// and every row must have the same number of columns. With a header the first row names the
+/// columns, and each name must be usable as an environment variable.
+pub fn parse_command_arg_file(
+    contents: &str,
+    delimiter: Option<Delimiter>,
+    has_header: bool,
+) -> anyhow::Result<CommandArgFile> {
+    let mut rows = match delimiter {
+        None => contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| vec![line.to_string()])
+            .collect::<Vec<_>>(),
+        Some(delimiter) => {
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .flexible(true)
+                .trim(csv::Trim::All)
+                .delimiter(delimiter.byte())
+                .from_reader(contents.as_bytes());
+            let mut rows = Vec::new();
+            for record in reader.records() {
+                let record = record?;
+                if record.iter().all(str::is_empty) {
+                    continue;
+                }
+                let row = record.iter().map(str::to_string).collect::<Vec<_>>();
+                if let Some(first) = rows.first().map(Vec::len)
+                    && first != row.len()
+                {
+                    let line = record.position().map_or(0, csv::Position::line);
+                    return Err(anyhow!(
+                        "Line {line} has {} columns, expected {first}",
+                        row.len()
+                    ));
+                }
+                rows.push(row);
+            }
+            rows
+        }
+    };
+    let header = if has_header && !rows.is_empty() {
+        let header = rows.remove(0);
+        if let Some(name) = header.iter().find(|name| !is_env_var_name(name)) {
+            return Err(anyhow!(
+                "Header \"{name}\" is not a valid environment variable name"
+            ));
+        }
+        if let Some(name) = header
+            .iter()
+            .find(|name| RESERVED_NAMES.contains(&name.as_str()))
+        {
+            return Err(anyhow!(
+                "Header \"{name}\" would replace an environment variable each container relies \
+                 on - rename the column"
+            ));
+        }
+        Some(header)
+    } else {
+        None
+    };
+    if rows.is_empty() {
+        return Err(anyhow!("No arguments found"));
+    }
+    Ok(CommandArgFile { header, rows })
+}
+
+fn is_env_var_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+#[test]
+fn parses_command_arg_file() {
+    assert_eq!(
+        vec![vec!["a"], vec!["b"], vec!["c"]],
+        parse_command_arg_file(
+            "
+     a
+
+     b
+
+c
+
+",
+            None,
+            false
+        )
+        .unwrap()
+        .rows
+    )
+}
+
+#[test]
+fn parses_command_arg_file_columns() {
+    assert_eq!(
+        vec![
+            vec!["M68123456", "ses 1", "/out"],
+            vec!["M68654321", "ses, 2", "/out"]
+        ],
+        parse_command_arg_file(
+            "
+M68123456 , ses 1,/out
+
+M68654321,\"ses, 2\",/out
+",
+            Some(Delimiter::Comma),
+            false
+        )
+        .unwrap()
+        .rows
+    )
+}
+
+#[test]
+fn rejects_ragged_command_arg_file() {
+    assert!(parse_command_arg_file("a\tb\nc\n", Some(Delimiter::Tab), false).is_err())
+}
+
+#[test]
+fn parses_command_arg_file_header() {
+    let file = parse_command_arg_file(
+        "SUBJECT\tSESSION\nM68123456\t1\n",
+        Some(Delimiter::Tab),
+        true,
+    )
+    .unwrap();
+    assert_eq!(vec!["SUBJECT", "SESSION"], file.header.unwrap());
+    assert_eq!(vec![vec!["M68123456", "1"]], file.rows);
+}
+
+#[test]
+fn rejects_invalid_header() {
+    assert!(parse_command_arg_file("SUBJECT,2ND\na,b\n", Some(Delimiter::Comma), true).is_err());
+    for name in RESERVED_NAMES {
+        let contents = format!("SUBJECT,{name}\na,b\n");
+        assert!(parse_command_arg_file(&contents, Some(Delimiter::Comma), true).is_err());
+    }
+}
+
+#[test]
+fn rejects_header_of_image_env() {
+    let catalog = crate::catalog::Catalog::parse(
+        "[images.tool]\nname = \"docker.io/lab/tool\"\nenv = { TOOL_DATA = \"/data\" }",
+    )
+    .unwrap();
+    let file =
+        parse_command_arg_file("SUBJECT,TOOL_DATA\na,b\n", Some(Delimiter::Comma), true).unwrap();
+    assert!(file.check_image_env(&catalog.parse_image("tool")).is_err());
+    assert!(file.check_image_env(&catalog.parse_image("ubuntu")).is_ok());
+}
@@ -1,49 +1,72 @@
 use anyhow::{Context, anyhow};
 use clap::Parser;
-use std::{io::Write, process::ExitStatus};
+use ihn_hpc_sbatch_array::{
+    VERSION,
+    argfile::read_command_arg_file,
+    catalog::Catalog,
+    dependency::AfterArgs,
+    gpu::GpuRequest,
+    job::{JobSpec, SubmitOptions, submit},
+    manifest,
+    sbatch::{SbatchOptions, check_raw_args},
+    script::ContainerSpec,
+    site::Site,
+    slurm,
+    template::parse_command,
+};
+use std::process::ExitStatus;
 
 #[derive(Parser)]
-#[command(version = option_env!("IHN_HPC_SBATCH_ARRAY_VERSION").unwrap_or("debug"), verbatim_doc_comment)]
+#[command(version = VERSION, verbatim_doc_comment)]
+// Options of the tasks of a job array
+#[command(group(
+    clap::ArgGroup::new("array_options")
+        .args(["max_tasks", "delimiter", "header", "log_dir", "max_array_size"])
+        .multiple(true)
+        .requires("arg_file")
+))]
 struct Args {
-    /// Podman image tag - ignored when IMAGE is fully qualified
-    #[arg(long)]
-    tag: Option<String>,
-    /// Additional args to sbatch
-    #[arg(long, allow_hyphen_values = true)]
-    sbatch_args: Option<String>,
-    /// Additional args to podman
-    #[arg(long, allow_hyphen_values = true)]
-    podman_args: Option<String>,
+    /// GPUs for the job as MODEL:COUNT, e.g. "a100:2", or "any:1" for any model
+    ///
+    /// The GPUs are checked against the GRES the cluster advertises, and only the allocated GPUs
+    /// are visible inside the container.
+    #[arg(long, default_value = "a100:1")]
+    gpus: GpuRequest,
+    #[command(flatten)]
+    options: SubmitOptions,
+    #[command(flatten)]
+    sbatch_options: SbatchOptions,
+    #[command(flatten)]
+    after: AfterArgs,
+    /// Path to a plaintext file containing one argument per line - submits a job array with one
+    /// task per line instead of a single job
+    ///
+    /// Each task is allocated its own --gpus and passed its line as the one argument to COMMAND,
+    /// as with ihn-hpc-sbatch-array. The job array is recorded so that
+    /// "ihn-hpc-sbatch-array status JOBID" and "ihn-hpc-sbatch-array retry JOBID" work on it.
+    #[arg(long, conflicts_with = "command_args")]
+    arg_file: Option<std::path::PathBuf>,
     /// Podman image - short-hand identifier or qualified name
     ///
     /// IMAGE specifies the podman image for the container. A short-hand identifier, e.g.
-    /// "freesurfer", may be used for known images. Otherwise IMAGE is passed directly to
+    /// "freesurfer", may be used for images in the built-in catalog, the site-wide catalog of the
+    /// site profile, or ~/.config/ihn-hpc/images.toml. Otherwise IMAGE is passed directly to
     /// podman-run.
-    #[arg(value_parser = parse_image)]
-    image: Image,
+    image: String,
     /// Command to execute inside the container
     ///
     /// COMMAND specifies the command executed inside the container. If COMMAND has a shell script
     /// extension (.sh) and exists on the host it is treated as a user-defined shell script and
     /// mounted inside the container.
+    ///
+    /// With --arg-file, COMMAND may be a template with placeholders such as {1} filled in from
+    /// each line, as with ihn-hpc-sbatch-array.
     command: String,
+    /// Arguments passed to COMMAND
     #[arg(trailing_var_arg = true, allow_hyphen_values = true)]
     command_args: Vec<String>,
 }
 
-#[derive(Clone)]
-enum Image {
-    Freesurfer,
-    QualifiedName(String),
-}
-
-fn parse_image(s: &str) -> anyhow::Result<Image> {
-    match s.to_lowercase().as_str() {
-        "freesurfer" => Ok(Image::Freesurfer),
-        _ => Ok(Image::QualifiedName(s.to_string())),
-    }
-}
-
 fn main() -> std::process::ExitCode {
     match run() {
         Ok(status) => {
@@ -63,85 +86,115 @@ fn main() -> std::process::ExitCode {
 
 fn run() -> anyhow::Result<ExitStatus> {
     let args = Args::parse();
-    let (command, command_volume_arg) = {
-        let command_path = std::path::Path::new(&args.command);
-        if command_path.exists() && command_path.extension().is_some_and(|ext| ext == "sh") {
-            let mounted_command_path = std::fs::canonicalize(command_path).with_context(||
-                format!("It looks like \"{command}\" is a shell script, but a canonical path cannot be determined for mounting in each container", command=args.command)
-            )?.to_str().with_context(||format!("\"{command}\" is not valid UTF-8", command=args.command))?.to_string();
-            (
-                mounted_command_path.clone(),
-                format!(
-                    "-v {}:{}",
-                    mounted_command_path.clone(),
-                    mounted_command_path
-                ),
-            )
-        } else {
-            (args.command, "".to_string())
-        }
+    match slurm::gpu_gres() {
+        Ok(advertised) => args.gpus.validate(&advertised)?,
+        Err(what) => eprintln!("WARN: {what:#}, --gpus {} is not checked", args.gpus),
+    }
+    let command_arg_file = match &args.arg_file {
+        Some(path) => Some(read_command_arg_file(
+            path,
+            args.options.delimiter,
+            args.options.header,
+        )?),
+        None => None,
     };
-    let mut sbatch_command = std::process::Command::new("sbatch");
-    sbatch_command.arg("--gres=gpu:a100:1");
-    if let Some(args) = args.sbatch_args {
-        sbatch_command.args(args.split_whitespace());
+    let sbatch_args = args.options.sbatch_args()?;
+    let mut reserved = vec![
+        ("gres", "use --gpus instead"),
+        ("gpus", "use --gpus instead"),
+        ("dependency", "use --dependency instead"),
+    ];
+    if args.arg_file.is_some() {
+        reserved.push((
+            "array",
+            "each line of --arg-file is a task, see --max-tasks",
+        ));
+        reserved.push(("output", "use --output or --log-dir instead"));
+        reserved.push(("error", "use --error or --log-dir instead"));
     }
-    let mut sbatch_child = sbatch_command
-        .stdin(std::process::Stdio::piped())
-        .spawn()
-        .context("Unable to invoke sbatch")?;
-    if let Some(mut stdin) = sbatch_child.stdin.take() {
-        writeln!(
-            stdin,
-            "#!/bin/bash
-set -u
-export TMPDIR=/ssd/home/$USER/TEMP
-srun --ntasks=1 podman run --rm \
-    --security-opt=label=disable \
-    --device=nvidia.com/gpu=all \
-    -v \"$HOME\":\"$HOME\" \
-    -e HPC_HOME=\"$HOME\" \
-    -v /mnt/home/shared/:/mnt/home/shared/ \
-    {command_volume_arg} \
-    {additional_podman_args} \
-    --authfile /mnt/apps/etc/auth.json \
-    --entrypoint {command} \
-    {podman_args} \
-    {image} {command_args}",
-            additional_podman_args = podman_args_for_image(&args.image),
-            podman_args = args.podman_args.unwrap_or("".to_string()),
-            image = qualified_image_name(args.image, args.tag),
-            command_args = args.command_args.join(" ")
-        )?;
-    } else {
-        return Err(anyhow!("Unable to take stdin of sbatch"));
+    check_raw_args(&sbatch_args, &args.sbatch_options, &reserved)?;
+    if args.arg_file.is_none() && !args.after.after_corr.is_empty() {
+        return Err(anyhow!(
+            "--after-corr pairs the tasks of job arrays and requires --arg-file"
+        ));
     }
-    Ok(sbatch_child.wait()?)
-}
-
-fn podman_args_for_image(c: &Image) -> &'static str {
-    match c {
-        Image::Freesurfer => {
-            "\
--v /mnt/apps/etc/fs_license.txt:/usr/local/freesurfer/.license:ro \
--v /opt/matlab/runtime/R2019b/v97/:/usr/local/freesurfer/MCRv97 \
--e FS_LICENSE=/usr/local/freesurfer/.license"
-        }
-        Image::QualifiedName(_) => "",
+    let site = Site::load()?;
+    let image = Catalog::load(&site)?.parse_image(&args.image);
+    if let Some(command_arg_file) = &command_arg_file {
+        command_arg_file.check_image_env(&image)?;
     }
-}
-
-fn qualified_image_name(image: Image, tag: Option<String>) -> String {
-    match image {
-        Image::Freesurfer => format!(
-            "docker.io/freesurfer/freesurfer:{}",
-            tag.unwrap_or_else(|| "7.3.2".to_string())
-        ),
-        Image::QualifiedName(n) => {
-            if let Some(t) = tag {
-                eprintln!("WARN: ignoring tag \"{t}\"");
+    // Only tasks of an --arg-file have arguments to fill in a template with
+    let (command, template) = match &command_arg_file {
+        Some(command_arg_file) => parse_command(&args.command, command_arg_file)?,
+        None => (args.command, None),
+    };
+    let mut container = ContainerSpec::new(image, args.options.tag.clone(), command)?;
+    container.podman_args = args.options.podman_args()?;
+    container.gpu = true;
+    if !args.options.no_pin {
+        container
+            .pin(&site)
+            .context("Unable to pin the image to a digest (--no-pin runs it by tag)")?;
+    }
+    let Some(command_arg_file) = command_arg_file else {
+        // A single job runs COMMAND once with COMMAND_ARGS
+        let spec = JobSpec {
+            container,
+            sbatch_options: args.sbatch_options,
+            sbatch_args,
+            max_tasks: 1,
+            gpus: Some(args.gpus),
+            after: args.after.resolve(1),
+            header: None,
+            template: None,
+            row_numbers: None,
+            log_dir: None,
+            rows: vec![args.command_args],
+        };
+        let batch_script = spec.job_script(&site, spec.dependency(0, None)?);
+        if args.options.dry_run {
+            println!("image: {}", spec.container.image_reference());
+            println!("{batch_script}");
+            return Ok(ExitStatus::default());
+        }
+        let script_sha256 = manifest::script_sha256(&spec)?;
+        let output = slurm::sbatch(&batch_script.sbatch_args, &batch_script.script)?;
+        if output.status.success() {
+            let job_id = slurm::parse_job_id(&output.stdout)?;
+            println!("Submitted batch job {job_id}");
+            // Recorded as a submission of one row, like the job arrays of --arg-file
+            let arrays = vec![manifest::SubmittedArray {
+                job_id,
+                first_row: 0,
+                len: 1,
+            }];
+            if let Err(what) = manifest::Manifest::new(spec, arrays, script_sha256).save() {
+                eprintln!("WARN: Unable to record submission: {what:#}");
             }
-            n
         }
-    }
+        return Ok(output.status);
+    };
+    let mut spec = JobSpec {
+        container,
+        sbatch_options: args.sbatch_options,
+        sbatch_args,
+        max_tasks: args.options.max_tasks,
+        gpus: Some(args.gpus),
+        after: args.after.resolve(command_arg_file.rows.len()),
+        header: command_arg_file.header,
+        template,
+        row_numbers: None,
+        log_dir: None,
+        rows: command_arg_file.rows,
+    };
+    spec.log_dir = match args.options.log_dir {
+        Some(log_dir) => Some(log_dir),
+        None => spec.default_log_dir()?,
+    };
+    submit(
+        &site,
+        spec,
+        args.options.max_array_size,
+        args.options.dry_run,
+    )
 }
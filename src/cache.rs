@@ -0,0 +1,418 @@
+//! Managing the podman images cached in the local storage of compute nodes.
+
+use anyhow::anyhow;
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    script::shell_quote,
+    site::Site,
+    slurm,
+    table::{format_kib, print_table},
+};
+
+/// Pulls `image` on each node and reports the digest each node ended up with.
+pub fn cache_image(
+    site: &Site,
+    image: &str,
+    nodes: &[String],
+    partition: Option<&str>,
+) -> anyhow::Result<()> {
+    let image = shell_quote(image);
+    let outputs = slurm::run_on_nodes(
+        nodes,
+        partition,
+        &format!(
+            "{export_tmpdir}
+podman pull --quiet --authfile {authfile} {image} >/dev/null &&
+podman image inspect --format '{{{{.Digest}}}}' {image}",
+            export_tmpdir = site.export_tmpdir(),
+            authfile = shell_quote(&site.authfile),
+        ),
+    )?;
+    let mut table = vec![["NODE", "STATUS", "DIGEST"].map(str::to_string).to_vec()];
+    let mut digests = BTreeSet::new();
+    for output in &outputs {
+        let last_line = output.lines.last().cloned().unwrap_or_default();
+        if output.success() {
+            digests.insert(last_line.clone());
+            table.push(vec![output.node.clone(), "OK".to_string(), last_line]);
+        } else {
+            table.push(vec![output.node.clone(), "FAILED".to_string(), last_line]);
+        }
+    }
+    print_table(&table);
+    if digests.len() > 1 {
+        eprintln!("WARN: nodes pulled different digests - the tag moved while pulling");
+    }
+    let failed = outputs.iter().filter(|output| !output.success()).count();
+    if failed > 0 {
+        return Err(anyhow!(
+            "Unable to cache the image on {failed} of {} nodes",
+            outputs.len()
+        ));
+    }
+    Ok(())
+}
+
+/// An image in a node's podman storage, as listed by "podman images --format json".
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct NodeImage {
+    pub id: String,
+    /// Repository names with tags, none for dangling images
+    #[serde(default)]
+    pub names: Option<Vec<String>>,
+    #[serde(default)]
+    pub digest: String,
+    /// Unix time
+    #[serde(default)]
+    pub created: u64,
+    /// Bytes
+    #[serde(default)]
+    pub size: u64,
+}
+
+impl NodeImage {
+    /// The image's names, or "<none>" for a dangling image.
+    pub fn names(&self) -> Vec<String> {
+        match &self.names {
+            Some(names) if !names.is_empty() => names.clone(),
+            _ => vec!["<none>".to_string()],
+        }
+    }
+}
+
+/// Lists the images in the podman storage of each node. Nodes where this fails are left out
+/// with a warning.
+pub fn node_images(
+    site: &Site,
+    nodes: &[String],
+    partition: Option<&str>,
+) -> anyhow::Result<BTreeMap<String, Vec<NodeImage>>> {
+    let outputs = slurm::run_on_nodes(
+        nodes,
+        partition,
+        &format!(
+            "{}
+podman images --format json 2>/dev/null",
+            site.export_tmpdir()
+        ),
+    )?;
+    let mut images = BTreeMap::new();
+    for output in outputs {
+        let listed = if output.success() {
+            serde_json::from_str::<Vec<NodeImage>>(&output.lines.join("\n"))
+                .map_err(anyhow::Error::from)
+        } else {
+            Err(anyhow!("podman images failed"))
+        };
+        match listed {
+            Ok(listed) => {
+                images.insert(output.node, listed);
+            }
+            Err(what) => eprintln!("WARN: skipping {}: {what:#}", output.node),
+        }
+    }
+    Ok(images)
+}
+
+/// Whether an image named `name` matches `filter`, a repository or a repository with a tag.
+/// Like podman, a filter without a registry matches in any registry, e.g. "ubuntu" matches
+/// "docker.io/library/ubuntu:22.04".
+pub fn matches_image(name: &str, filter: &str) -> bool {
+    let matches = |name: &str| {
+        name == filter
+            || name
+                .strip_prefix(filter)
+                .is_some_and(|rest| rest.starts_with(':') || rest.starts_with('@'))
+    };
+    let without_registry = match name.split_once('/') {
+        Some((registry, rest)) if registry.contains(['.', ':']) || registry == "localhost" => {
+            Some(rest)
+        }
+        _ => None,
+    };
+    matches(name)
+        || without_registry
+            .is_some_and(|rest| matches(rest) || rest.strip_prefix("library/").is_some_and(matches))
+}
+
+/// Prints which nodes cache which images, marking images missing from some nodes with "!".
+pub fn print_inventory(images: &BTreeMap<String, Vec<NodeImage>>, filters: &[String]) {
+    let mut cached = BTreeMap::<(String, String), BTreeSet<&str>>::new();
+    for (node, node_images) in images {
+        for image in node_images {
+            for name in image.names() {
+                if filters.is_empty() || filters.iter().any(|filter| matches_image(&name, filter)) {
+                    cached
+                        .entry((name, short_digest(&image.digest)))
+                        .or_default()
+                        .insert(node);
+                }
+            }
+        }
+    }
+    let mut header = vec!["".to_string(), "IMAGE".to_string(), "DIGEST".to_string()];
+    header.extend(images.keys().cloned());
+    let mut table = vec![header];
+    let mut incomplete = 0;
+    for ((name, digest), nodes) in &cached {
+        let everywhere = nodes.len() == images.len();
+        if !everywhere {
+            incomplete += 1;
+        }
+        let mut row = vec![
+            if everywhere { "" } else { "!" }.to_string(),
+            name.clone(),
+            digest.clone(),
+        ];
+        row.extend(images.keys().map(|node| {
+            if nodes.contains(node.as_str()) {
+                "x"
+            } else {
+                "-"
+            }
+            .to_string()
+        }));
+        table.push(row);
+    }
+    print_table(&table);
+    if incomplete > 0 {
+        println!();
+        println!("! {incomplete} images are not cached on every node");
+    }
+}
+
+/// Removes the images that match none of `keep` and, with `older_than`, were created longer
+/// ago than that, from the podman storage of each node.
+pub fn prune_images(
+    site: &Site,
+    images: &BTreeMap<String, Vec<NodeImage>>,
+    partition: Option<&str>,
+    keep: &[String],
+    older_than: Option<std::time::Duration>,
+    dry_run: bool,
+) -> anyhow::Result<()> {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)?
+        .as_secs();
+    let unmatched = keep
+        .iter()
+        .filter(|filter| {
+            !images
+                .values()
+                .flatten()
+                .any(|image| image.names().iter().any(|name| matches_image(name, filter)))
+        })
+        .map(String::as_str)
+        .collect::<Vec<_>>();
+    if !unmatched.is_empty() {
+        let what = format!(
+            "--keep {} matches no image on any node",
+            unmatched.join(", ")
+        );
+        if !dry_run {
+            return Err(anyhow!("{what}, nothing was removed"));
+        }
+        eprintln!("WARN: {what}");
+    }
+    let mut candidates = BTreeMap::new();
+    for (node, node_images) in images {
+        let prunable = node_images
+            .iter()
+            .filter(|image| is_prunable(image, keep, older_than, now))
+            .collect::<Vec<_>>();
+        if !prunable.is_empty() {
+            candidates.insert(node.as_str(), prunable);
+        }
+    }
+    if candidates.is_empty() {
+        println!("Nothing to prune");
+        return Ok(());
+    }
+    if dry_run {
+        let mut table = vec![
+            ["NODE", "IMAGE", "DIGEST", "AGE", "SIZE"]
+                .map(str::to_string)
+                .to_vec(),
+        ];
+        for (node, prunable) in &candidates {
+            for image in prunable {
+                table.push(vec![
+                    node.to_string(),
+                    image.names().join(","),
+                    short_digest(&image.digest),
+                    format!("{}d", now.saturating_sub(image.created) / 86400),
+                    format_kib(image.size / 1024),
+                ]);
+            }
+        }
+        print_table(&table);
+        return Ok(());
+    }
+
+    // Images are removed by their names - podman refuses to remove an image with several names
+    // by its ID - and dangling images by their ID
+    let mut script = format!(
+        "{}
+remove() {{
+  id=$1
+  shift
+  if podman rmi \"$@\" >/dev/null 2>&1; then echo \"removed $id\"; else echo \"failed $id\"; fi
+}}
+case $SLURMD_NODENAME in
+",
+        site.export_tmpdir()
+    );
+    for (node, prunable) in &candidates {
+        script += &format!("{})\n", shell_quote(node));
+        for image in prunable {
+            let references = match &image.names {
+                Some(names) if !names.is_empty() => names.clone(),
+                _ => vec![image.id.clone()],
+            };
+            script += &format!(
+                "  remove {} {}\n",
+                shell_quote(&image.id),
+                references
+                    .iter()
+                    .map(|reference| shell_quote(reference))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            );
+        }
+        script += "  ;;\n";
+    }
+    script += "esac";
+    let nodes = candidates
+        .keys()
+        .map(|node| node.to_string())
+        .collect::<Vec<_>>();
+    let outputs = slurm::run_on_nodes(&nodes, partition, &script)?;
+
+    let mut table = vec![
+        ["NODE", "REMOVED", "FAILED", "RECLAIMED"]
+            .map(str::to_string)
+            .to_vec(),
+    ];
+    let mut failed_nodes = 0;
+    for output in &outputs {
+        let sizes = images[&output.node]
+            .iter()
+            .map(|image| (image.id.as_str(), image.size))
+            .collect::<BTreeMap<_, _>>();
+        let (mut removed, mut failed, mut reclaimed) = (0, 0, 0);
+        for line in &output.lines {
+            if let Some(id) = line.strip_prefix("removed ") {
+                removed += 1;
+                reclaimed += sizes.get(id).copied().unwrap_or_default();
+            } else if line.starts_with("failed ") {
+                failed += 1;
+            }
+        }
+        if failed > 0 || !output.success() {
+            failed_nodes += 1;
+        }
+        table.push(vec![
+            output.node.clone(),
+            removed.to_string(),
+            failed.to_string(),
+            format_kib(reclaimed / 1024),
+        ]);
+    }
+    print_table(&table);
+    if failed_nodes > 0 {
+        return Err(anyhow!(
+            "Unable to remove every image on {failed_nodes} of {} nodes - images used by containers are kept",
+            outputs.len()
+        ));
+    }
+    Ok(())
+}
+
+/// An image is prunable when none of its names match `keep` and, with `older_than`, it was
+/// created longer ago than that. `now` is Unix time.
+fn is_prunable(
+    image: &NodeImage,
+    keep: &[String],
+    older_than: Option<std::time::Duration>,
+    now: u64,
+) -> bool {
+    let kept = image
+        .names()
+        .iter()
+        .any(|name| keep.iter().any(|filter| matches_image(name, filter)));
+    let old = older_than.is_none_or(|age| now.saturating_sub(image.created) > age.as_secs());
+    !kept && old
+}
+
+/// e.g. "sha256:0123456789ab"
+fn short_digest(digest: &str) -> String {
+    digest.chars().take("sha256:".len() + 12).collect()
+}
+
+#[test]
+fn parses_podman_images() {
+    let images: Vec<NodeImage> = serde_json::from_str(
+        r#"[
+  {
+    "Id": "4f0b4ad1",
+    "ParentId": "",
+    "RepoTags": null,
+    "Names": ["docker.io/freesurfer/freesurfer:7.3.2"],
+    "Digest": "sha256:0123456789abcdef",
+    "Size": 1234,
+    "Created": 1690000000
+  },
+  { "Id": "9a8b7c6d", "Names": null, "Digest": "sha256:fedcba", "Size": 10, "Created": 1 }
+]"#,
+    )
+    .unwrap();
+    assert_eq!(
+        vec!["docker.io/freesurfer/freesurfer:7.3.2"],
+        images[0].names()
+    );
+    assert_eq!(vec!["<none>"], images[1].names());
+}
+
+#[test]
+fn matches_images() {
+    let name = "docker.io/freesurfer/freesurfer:7.3.2";
+    assert!(matches_image(name, "docker.io/freesurfer/freesurfer"));
+    assert!(matches_image(name, name));
+    assert!(!matches_image(
+        name,
+        "docker.io/freesurfer/freesurfer:7.4.1"
+    ));
+    assert!(!matches_image(name, "docker.io/freesurfer/free"));
+    assert!(matches_image(name, "freesurfer/freesurfer:7.3.2"));
+    let ubuntu = "docker.io/library/ubuntu:22.04";
+    assert!(matches_image(ubuntu, "ubuntu"));
+    assert!(matches_image(ubuntu, "ubuntu:22.04"));
+    assert!(matches_image(ubuntu, "library/ubuntu"));
+    assert!(!matches_image(ubuntu, "ubuntu:24.04"));
+    assert!(!matches_image("ubuntu-base:22.04", "ubuntu"));
+}
+
+#[test]
+fn selects_prunable_images() {
+    let image = |names: &[&str], created| NodeImage {
+        id: "a1".to_string(),
+        names: Some(names.iter().map(|name| name.to_string()).collect()),
+        digest: String::new(),
+        created,
+        size: 0,
+    };
+    let keep = vec!["docker.io/freesurfer/freesurfer".to_string()];
+    let day = std::time::Duration::from_secs(86400);
+    let now = 100 * 86400;
+    let freesurfer = image(&["docker.io/freesurfer/freesurfer:7.3.2"], 0);
+    let old = image(&["docker.io/nvidia/cuda:11.8"], 0);
+    let new = image(&["docker.io/nvidia/cuda:12.2"], now - 86400);
+    let dangling = image(&[], 0);
+    assert!(!is_prunable(&freesurfer, &keep, Some(day), now));
+    assert!(is_prunable(&old, &keep, Some(day * 30), now));
+    assert!(!is_prunable(&new, &keep, Some(day * 30), now));
+    assert!(is_prunable(&new, &keep, None, now));
+    assert!(is_prunable(&dangling, &[], Some(day), now));
+}
@@ -0,0 +1,230 @@
+//! Known podman images, identified by short-hand names such as "freesurfer".
+//!
+//! The built-in catalog is extended by a site-wide catalog and a per-user catalog, each a TOML
+//! file of `[images.<short-hand>]` tables. Later catalogs replace entries of the same name.
+
+use anyhow::Context;
+use std::collections::BTreeMap;
+
+use crate::site::Site;
+
+const BUILT_IN_CATALOG: &str = include_str!("images.toml");
+
+#[derive(Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Catalog {
+    #[serde(default)]
+    images: BTreeMap<String, CatalogImage>,
+}
+
+#[derive(Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CatalogImage {
+    /// Registry name without a tag, e.g. "docker.io/freesurfer/freesurfer"
+    pub name: String,
+    /// Tag used when --tag is not given
+    pub tag: Option<String>,
+    /// Volumes mounted in each container, in podman's "SOURCE:DESTINATION[:OPTIONS]" form - may
+    /// refer to the site profile's paths as ${name}
+    #[serde(default)]
+    pub mounts: Vec<String>,
+    /// Environment variables set in each container
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub resources: Resources,
+}
+
+/// Default sbatch resource requests, overridden by --sbatch-args
+#[derive(Clone, Default, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Resources {
+    pub cpus_per_task: Option<u32>,
+    pub mem: Option<String>,
+    pub time: Option<String>,
+}
+
+#[derive(Clone)]
+pub enum Image {
+    Known(CatalogImage),
+    QualifiedName(String),
+}
+
+impl Catalog {
+    /// Loads the built-in, site-wide, and user catalogs, and expands the site's paths in their
+    /// mounts. Missing catalog files are skipped.
+    pub fn load(site: &Site) -> anyhow::Result<Catalog> {
+        let mut catalog = Catalog::parse(BUILT_IN_CATALOG).context("Invalid built-in catalog")?;
+        let mut paths = vec![std::path::PathBuf::from(&site.catalog)];
+        paths.extend(user_catalog());
+        for path in paths {
+            let contents = match std::fs::read_to_string(&path) {
+                Ok(contents) => contents,
+                Err(what) if what.kind() == std::io::ErrorKind::NotFound => continue,
+                Err(what) => {
+                    return Err(what).with_context(|| format!("Unable to read catalog {path:?}"));
+                }
+            };
+            catalog.extend(
+                Catalog::parse(&contents).with_context(|| format!("Invalid catalog {path:?}"))?,
+            );
+        }
+        catalog.expand_paths(site)?;
+        Ok(catalog)
+    }
+
+    /// Replaces ${name} in the mounts of each image with the site's location `name`.
+    fn expand_paths(&mut self, site: &Site) -> anyhow::Result<()> {
+        for (id, image) in &mut self.images {
+            for mount in &mut image.mounts {
+                *mount = site
+                    .expand_paths(mount)
+                    .with_context(|| format!("Invalid mount of image \"{id}\""))?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn parse(contents: &str) -> anyhow::Result<Catalog> {
+        let catalog: Catalog = toml::from_str(contents)?;
+        Ok(Catalog {
+            images: catalog
+                .images
+                .into_iter()
+                .map(|(id, image)| (id.to_lowercase(), image))
+                .collect(),
+        })
+    }
+
+    /// Adds the entries of `other`, replacing entries of the same name.
+    pub fn extend(&mut self, other: Catalog) {
+        self.images.extend(other.images);
+    }
+
+    /// Resolves a short-hand identifier, ignoring case. Anything else is a qualified name.
+    pub fn parse_image(&self, s: &str) -> Image {
+        match self.images.get(&s.to_lowercase()) {
+            Some(image) => Image::Known(image.clone()),
+            None => Image::QualifiedName(s.to_string()),
+        }
+    }
+}
+
+/// $XDG_CONFIG_HOME/ihn-hpc/images.toml, falling back to ~/.config/ihn-hpc/images.toml
+fn user_catalog() -> Option<std::path::PathBuf> {
+    let config_home = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => std::path::PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_home.join("ihn-hpc/images.toml"))
+}
+
+/// The podman-run arguments mounting and configuring what a known image requires.
+pub fn podman_args_for_image(image: &Image) -> Vec<String> {
+    match image {
+        Image::Known(image) => image
+            .mounts
+            .iter()
+            .flat_map(|mount| ["-v".to_string(), mount.clone()])
+            .chain(
+                image
+                    .env
+                    .iter()
+                    .flat_map(|(name, value)| ["-e".to_string(), format!("{name}={value}")]),
+            )
+            .collect(),
+        Image::QualifiedName(_) => Vec::new(),
+    }
+}
+
+/// The sbatch arguments requesting the default resources of a known image.
+pub fn sbatch_args_for_image(image: &Image) -> Vec<String> {
+    match image {
+        Image::Known(image) => {
+            let resources = &image.resources;
+            resources
+                .cpus_per_task
+                .map(|cpus| format!("--cpus-per-task={cpus}"))
+                .into_iter()
+                .chain(resources.mem.as_ref().map(|mem| format!("--mem={mem}")))
+                .chain(resources.time.as_ref().map(|time| format!("--time={time}")))
+                .collect()
+        }
+        Image::QualifiedName(_) => Vec::new(),
+    }
+}
+
+pub fn qualified_image_name(image: Image, tag: Option<String>) -> String {
+    match image {
+        Image::Known(image) => match tag.or(image.tag) {
+            Some(tag) => format!("{}:{tag}", image.name),
+            None => image.name,
+        },
+        Image::QualifiedName(n) => {
+            if let Some(t) = tag {
+                eprintln!("WARN: ignoring tag \"{t}\"");
+            }
+            n
+        }
+    }
+}
+
+#[test]
+fn resolves_built_in_freesurfer() {
+    let mut catalog = Catalog::parse(BUILT_IN_CATALOG).unwrap();
+    catalog.expand_paths(&crate::site::built_in()).unwrap();
+    let image = catalog.parse_image("FreeSurfer");
+    assert_eq!(
+        vec![
+            "-v",
+            "/mnt/apps/etc/fs_license.txt:/usr/local/freesurfer/.license:ro",
+            "-v",
+            "/opt/matlab/runtime/R2019b/v97/:/usr/local/freesurfer/MCRv97",
+            "-e",
+            "FS_LICENSE=/usr/local/freesurfer/.license",
+        ],
+        podman_args_for_image(&image)
+    );
+    assert_eq!(
+        "docker.io/freesurfer/freesurfer:7.3.2",
+        qualified_image_name(image.clone(), None)
+    );
+    assert_eq!(
+        "docker.io/freesurfer/freesurfer:7.4.1",
+        qualified_image_name(image, Some("7.4.1".to_string()))
+    );
+}
+
+#[test]
+fn later_catalogs_replace_entries() {
+    let mut catalog = Catalog::parse(BUILT_IN_CATALOG).unwrap();
+    catalog.extend(
+        Catalog::parse(
+            r#"
+[images.freesurfer]
+name = "docker.io/freesurfer/freesurfer"
+tag = "7.4.1"
+
+[images.fmriprep]
+name = "docker.io/nipreps/fmriprep"
+tag = "24.1.1"
+resources = { cpus_per_task = 8, mem = "32G", time = "24:00:00" }
+"#,
+        )
+        .unwrap(),
+    );
+    let freesurfer = catalog.parse_image("freesurfer");
+    assert!(podman_args_for_image(&freesurfer).is_empty());
+    assert_eq!(
+        "docker.io/freesurfer/freesurfer:7.4.1",
+        qualified_image_name(freesurfer, None)
+    );
+    assert_eq!(
+        vec!["--cpus-per-task=8", "--mem=32G", "--time=24:00:00"],
+        sbatch_args_for_image(&catalog.parse_image("fmriprep"))
+    );
+    assert!(matches!(
+        catalog.parse_image("docker.io/library/alpine"),
+        Image::QualifiedName(_)
+    ));
+}
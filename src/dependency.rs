@@ -0,0 +1,196 @@
+//! Starting a submission after earlier ones, named by the job ID they printed. A submission split
+//! into several job arrays is waited on as a whole, as recorded in its manifest.
+
+use anyhow::anyhow;
+
+use crate::manifest::{Manifest, SubmittedArray};
+
+/// --after-ok, --after-any, and --after-corr, each naming earlier submissions by job ID.
+#[derive(clap::Args)]
+pub struct AfterArgs {
+    /// Start once the job JOBID, or every job array of the submission that printed it, has
+    /// completed successfully - may be repeated
+    #[arg(long, value_name = "JOBID", help_heading = "Sbatch options", value_parser = parse_job_id)]
+    pub after_ok: Vec<String>,
+    /// Start once the job JOBID, or every job array of the submission that printed it, has ended
+    /// in any state - may be repeated
+    #[arg(long, value_name = "JOBID", help_heading = "Sbatch options", value_parser = parse_job_id)]
+    pub after_any: Vec<String>,
+    /// Start each task once the task of the same argument in the job array JOBID has completed
+    /// successfully - may be repeated
+    ///
+    /// Tasks are matched by their position in the argument file, so both submissions should list
+    /// the same arguments in the same order.
+    #[arg(long, value_name = "JOBID", help_heading = "Sbatch options", value_parser = parse_job_id)]
+    pub after_corr: Vec<String>,
+}
+
+impl AfterArgs {
+    /// Looks up the job arrays of each submission to wait on. `tasks` is the number of tasks of
+    /// the new submission, which --after-corr submissions should match.
+    pub fn resolve(&self, tasks: usize) -> Vec<After> {
+        let kinds = [
+            (AfterKind::Ok, &self.after_ok),
+            (AfterKind::Any, &self.after_any),
+            (AfterKind::Corr, &self.after_corr),
+        ];
+        let mut afters = Vec::new();
+        for (kind, job_ids) in kinds {
+            for job_id in job_ids {
+                let after = After::resolve(kind, job_id);
+                let after_tasks = after.arrays.iter().map(|array| array.len).sum::<usize>();
+                if kind == AfterKind::Corr && !after.arrays.is_empty() && after_tasks != tasks {
+                    eprintln!(
+                        "WARN: Job {job_id} has {after_tasks} tasks, but {tasks} are being \
+                         submitted - --after-corr matches them by position"
+                    );
+                }
+                afters.push(after);
+            }
+        }
+        afters
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AfterKind {
+    /// Once the job has completed successfully
+    Ok,
+    /// Once the job has ended
+    Any,
+    /// Once the corresponding task of the job array has completed successfully
+    Corr,
+}
+
+impl AfterKind {
+    /// The sbatch dependency type
+    fn name(self) -> &'static str {
+        match self {
+            AfterKind::Ok => "afterok",
+            AfterKind::Any => "afterany",
+            AfterKind::Corr => "aftercorr",
+        }
+    }
+}
+
+/// An earlier submission to wait on.
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct After {
+    pub kind: AfterKind,
+    /// The job ID as given
+    pub job_id: String,
+    /// The job arrays of the submission, as recorded in its manifest - none for jobs that were not
+    /// submitted by these tools
+    pub arrays: Vec<SubmittedArray>,
+}
+
+impl After {
+    /// Looks up the submission that printed `job_id`. Jobs without a manifest are waited on as is.
+    pub fn resolve(kind: AfterKind, job_id: &str) -> After {
+        let arrays = Manifest::load(job_id)
+            .map(|manifest| manifest.arrays)
+            .unwrap_or_default();
+        After {
+            kind,
+            job_id: job_id.to_string(),
+            arrays,
+        }
+    }
+
+    /// The sbatch dependency of the job array running the arguments `first_row` onwards.
+    pub fn dependency(&self, first_row: usize) -> anyhow::Result<String> {
+        if self.arrays.is_empty() {
+            return Ok(format!("{}:{}", self.kind.name(), self.job_id));
+        }
+        let job_ids = match self.kind {
+            AfterKind::Ok | AfterKind::Any => self
+                .arrays
+                .iter()
+                .map(|array| array.job_id.as_str())
+                .collect::<Vec<_>>(),
+            // Tasks of the job array that starts at the same argument have the same indices
+            AfterKind::Corr => {
+                let array = self
+                    .arrays
+                    .iter()
+                    .find(|array| array.first_row == first_row)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "Job {} was split into job arrays at other arguments than this \
+                             submission, so their tasks do not correspond - submit with the same \
+                             --max-array-size",
+                            self.job_id
+                        )
+                    })?;
+                vec![array.job_id.as_str()]
+            }
+        };
+        Ok(format!("{}:{}", self.kind.name(), job_ids.join(":")))
+    }
+}
+
+/// Combines --dependency with the dependencies of `afters` for the job array running the arguments
+/// `first_row` onwards. All of them must be satisfied.
+pub fn combine(
+    dependency: Option<&str>,
+    afters: &[After],
+    first_row: usize,
+) -> anyhow::Result<Option<String>> {
+    let mut dependencies = dependency
+        .map(str::to_string)
+        .into_iter()
+        .collect::<Vec<_>>();
+    if dependency.is_some_and(|dependency| dependency.contains('?')) && !afters.is_empty() {
+        return Err(anyhow!(
+            "--dependency with \"?\", any of several dependencies, cannot be combined with \
+             --after-ok, --after-any, or --after-corr"
+        ));
+    }
+    for after in afters {
+        dependencies.push(after.dependency(first_row)?);
+    }
+    Ok((!dependencies.is_empty()).then(|| dependencies.join(",")))
+}
+
+fn parse_job_id(value: &str) -> anyhow::Result<String> {
+    if value.is_empty() || !value.chars().all(|c| c.is_ascii_digit()) {
+        return Err(anyhow!("\"{value}\" is not a job ID, e.g. \"1234\""));
+    }
+    Ok(value.to_string())
+}
+
+#[test]
+fn waits_on_every_array_of_a_submission() {
+    let arrays = [("1001", 0, 2), ("1002", 2, 1)]
+        .map(|(job_id, first_row, len)| SubmittedArray {
+            job_id: job_id.to_string(),
+            first_row,
+            len,
+        })
+        .to_vec();
+    let after = |kind| After {
+        kind,
+        job_id: "1001".to_string(),
+        arrays: arrays.clone(),
+    };
+    assert_eq!(
+        "afterok:1001:1002",
+        after(AfterKind::Ok).dependency(0).unwrap()
+    );
+    assert_eq!(
+        "aftercorr:1002",
+        after(AfterKind::Corr).dependency(2).unwrap()
+    );
+    assert!(after(AfterKind::Corr).dependency(1).is_err());
+    let untracked = After {
+        kind: AfterKind::Any,
+        job_id: "999".to_string(),
+        arrays: Vec::new(),
+    };
+    assert_eq!(
+        Some("singleton,afterany:999,aftercorr:1001".to_string()),
+        combine(Some("singleton"), &[untracked, after(AfterKind::Corr)], 0).unwrap()
+    );
+    assert!(combine(Some("afterok:1?afterok:2"), &[after(AfterKind::Ok)], 0).is_err());
+}
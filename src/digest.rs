@@ -0,0 +1,88 @@
+//! Pinning images to immutable digests so that every task runs the same image contents, however
+//! the image's tag moves after submission.
+
+use anyhow::anyhow;
+
+/// Resolves `image` to its digest, e.g. "sha256:0123...", from the local podman store or else from
+/// its registry with skopeo. Images already referenced by digest resolve to that digest.
+pub fn resolve(image: &str, authfile: &str) -> anyhow::Result<String> {
+    if let Some((_, digest)) = image.split_once('@') {
+        return Ok(digest.to_string());
+    }
+    if let Ok(output) = std::process::Command::new("podman")
+        .args(["image", "inspect", "--format", "{{.Digest}}", image])
+        .stderr(std::process::Stdio::null())
+        .output()
+        && output.status.success()
+        && let Some(digest) = parse_digest(&output.stdout)
+    {
+        return Ok(digest);
+    }
+    let output = std::process::Command::new("skopeo")
+        .args(["inspect", "--authfile", authfile, "--format", "{{.Digest}}"])
+        .arg(format!("docker://{image}"))
+        .output()
+        .map_err(|what| {
+            anyhow!("{image} is not in the local podman store and skopeo cannot be invoked: {what}")
+        })?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{image} is not in the local podman store and skopeo failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_digest(&output.stdout).ok_or_else(|| {
+        anyhow!(
+            "Unexpected skopeo output \"{}\"",
+            String::from_utf8_lossy(&output.stdout).trim()
+        )
+    })
+}
+
+fn parse_digest(stdout: &[u8]) -> Option<String> {
+    let digest = String::from_utf8_lossy(stdout).trim().to_string();
+    let hex = digest.strip_prefix("sha256:")?;
+    (hex.len() == 64 && hex.chars().all(|c| c.is_ascii_hexdigit())).then_some(digest)
+}
+
+/// References `image` by `digest` instead of by tag, e.g. "docker.io/freesurfer/freesurfer:7.3.2"
+/// becomes "docker.io/freesurfer/freesurfer@sha256:0123...".
+pub fn pinned_name(image: &str, digest: &str) -> String {
+    let repository = image
+        .split_once('@')
+        .map_or(image, |(repository, _)| repository);
+    let name_start = repository.rfind('/').map_or(0, |slash| slash + 1);
+    let repository = match repository[name_start..].rfind(':') {
+        Some(colon) => &repository[..name_start + colon],
+        None => repository,
+    };
+    format!("{repository}@{digest}")
+}
+
+#[test]
+fn pins_names() {
+    let digest = format!("sha256:{}", "0".repeat(64));
+    assert_eq!(
+        format!("docker.io/freesurfer/freesurfer@{digest}"),
+        pinned_name("docker.io/freesurfer/freesurfer:7.3.2", &digest)
+    );
+    assert_eq!(
+        format!("localhost:5000/alpine@{digest}"),
+        pinned_name("localhost:5000/alpine", &digest)
+    );
+    assert_eq!(
+        format!("alpine@{digest}"),
+        pinned_name(&format!("alpine:3@{digest}"), &digest)
+    );
+}
+
+#[test]
+fn parses_digests() {
+    let digest = format!("sha256:{}", "ab".repeat(32));
+    assert_eq!(
+        Some(digest.clone()),
+        parse_digest(format!("{digest}\n").as_bytes())
+    );
+    assert_eq!(None, parse_digest(b"\n"));
+    assert_eq!(None, parse_digest(b"sha256:xyz\n"));
+}
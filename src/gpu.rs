@@ -0,0 +1,135 @@
+//! GPU requests such as "a100:2", checked against the GRES the cluster advertises.
+
+use anyhow::anyhow;
+
+/// A number of GPUs of one model, or of any model.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct GpuRequest {
+    /// GRES type, e.g. "a100" - any model when none
+    pub model: Option<String>,
+    pub count: u32,
+}
+
+impl GpuRequest {
+    /// The sbatch --gres value, e.g. "gpu:a100:2".
+    pub fn gres(&self) -> String {
+        match &self.model {
+            Some(model) => format!("gpu:{model}:{}", self.count),
+            None => format!("gpu:{}", self.count),
+        }
+    }
+
+    /// Checks that some node of the cluster has this many GPUs of this model. `advertised` holds
+    /// the GPUs of each node configuration, as listed by `slurm::gpu_gres` - a cluster that
+    /// advertises none has no GPUs to allocate, so every request fails.
+    pub fn validate(&self, advertised: &[GpuRequest]) -> anyhow::Result<()> {
+        let fits = advertised.iter().any(|gpus| {
+            (self.model.is_none() || gpus.model == self.model) && gpus.count >= self.count
+        });
+        if fits {
+            return Ok(());
+        }
+        let mut available = advertised.iter().map(GpuRequest::gres).collect::<Vec<_>>();
+        available.sort();
+        available.dedup();
+        Err(anyhow!(
+            "No node has {} GPUs of model {} - the cluster advertises {}",
+            self.count,
+            self.model.as_deref().unwrap_or("any"),
+            if available.is_empty() {
+                "no GPUs".to_string()
+            } else {
+                available.join(", ")
+            }
+        ))
+    }
+}
+
+/// Formats as "MODEL:COUNT", the form --gpus takes.
+impl std::fmt::Display for GpuRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            self.model.as_deref().unwrap_or("any"),
+            self.count
+        )
+    }
+}
+
+/// Parses "MODEL:COUNT", where MODEL is a GRES type such as "a100" or "any".
+impl std::str::FromStr for GpuRequest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (model, count) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow!("Expected MODEL:COUNT, e.g. \"a100:2\" or \"any:1\""))?;
+        let count = count
+            .parse()
+            .ok()
+            .filter(|count| *count > 0)
+            .ok_or_else(|| anyhow!("GPU count \"{count}\" is not a positive number"))?;
+        let model = match model {
+            "" => return Err(anyhow!("Expected a GPU model or \"any\" before the count")),
+            "any" => None,
+            model => Some(model.to_string()),
+        };
+        Ok(GpuRequest { model, count })
+    }
+}
+
+#[test]
+fn parses_gpu_requests() {
+    let request = "a100:2".parse::<GpuRequest>().unwrap();
+    assert_eq!("gpu:a100:2", request.gres());
+    assert_eq!("gpu:1", "any:1".parse::<GpuRequest>().unwrap().gres());
+    assert!("a100".parse::<GpuRequest>().is_err());
+    assert!("a100:0".parse::<GpuRequest>().is_err());
+}
+
+#[test]
+fn validates_gpu_requests() {
+    let advertised = ["a100:4", "a40:2"].map(|gpus| gpus.parse::<GpuRequest>().unwrap());
+    assert!(
+        "a100:4"
+            .parse::<GpuRequest>()
+            .unwrap()
+            .validate(&advertised)
+            .is_ok()
+    );
+    assert!(
+        "any:3"
+            .parse::<GpuRequest>()
+            .unwrap()
+            .validate(&advertised)
+            .is_ok()
+    );
+    assert!(
+        "a40:3"
+            .parse::<GpuRequest>()
+            .unwrap()
+            .validate(&advertised)
+            .is_err()
+    );
+    assert!(
+        "h100:1"
+            .parse::<GpuRequest>()
+            .unwrap()
+            .validate(&advertised)
+            .is_err()
+    );
+}
+
+#[test]
+fn rejects_gpus_on_clusters_without_gpus() {
+    let what = "any:1"
+        .parse::<GpuRequest>()
+        .unwrap()
+        .validate(&[])
+        .unwrap_err();
+    assert_eq!(
+        "No node has 1 GPUs of model any - the cluster advertises no GPUs",
+        what.to_string()
+    );
+}
@@ -0,0 +1,533 @@
+//! Job arrays running one container per row of arguments.
+
+use anyhow::{Context, anyhow};
+use std::{path::Path, process::ExitStatus};
+
+use crate::{
+    argfile::Delimiter,
+    dependency::{self, After},
+    gpu::GpuRequest,
+    manifest,
+    sbatch::SbatchOptions,
+    script::{
+        BatchScript, ContainerSpec, command_args_script, deserialize_words, quote_words,
+        split_words,
+    },
+    site::Site,
+    slurm,
+    template::Template,
+};
+
+/// Command-line options of a submission, shared by ihn-hpc-sbatch-array and ihn-hpc-sbatch-gpu.
+#[derive(clap::Args)]
+pub struct SubmitOptions {
+    /// Podman image tag - ignored when IMAGE is fully qualified
+    #[arg(long)]
+    pub tag: Option<String>,
+    /// Run each task by tag instead of pinning it to the digest the tag points to at submission
+    #[arg(long)]
+    pub no_pin: bool,
+    /// The maximum number of simultaneous tasks
+    #[arg(long, default_value_t = 16)]
+    pub max_tasks: i32,
+    /// Split each line of the argument file into columns - one argument to COMMAND per column
+    #[arg(long, value_enum)]
+    pub delimiter: Option<Delimiter>,
+    /// Treat the first line of the argument file as column names - each column is exported to
+    /// COMMAND as an environment variable of the same name
+    #[arg(long, requires = "delimiter")]
+    pub header: bool,
+    /// Additional args to sbatch, split like a shell would, e.g. '--comment="two words"'
+    #[arg(long, allow_hyphen_values = true)]
+    pub sbatch_args: Option<String>,
+    /// An additional arg to sbatch, taken as is - may be repeated
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    pub sbatch_arg: Vec<String>,
+    /// Additional args to podman, split like a shell would
+    #[arg(long, allow_hyphen_values = true)]
+    pub podman_args: Option<String>,
+    /// An additional arg to podman, taken as is - may be repeated
+    #[arg(long, value_name = "ARG", allow_hyphen_values = true)]
+    pub podman_arg: Vec<String>,
+    /// Directory for the logs of each task, in a directory named after the job ID
+    ///
+    /// Defaults to ~/hpc-logs/JOB_NAME, where JOB_NAME is --job-name or the name of COMMAND. The
+    /// stdout and stderr of each task are named JOBID_TASK.out and JOBID_TASK.err, and are also
+    /// linked to as files named after the task's first argument, e.g. M68123456.out. index.tsv
+    /// lists every task with its arguments. Not used with --output or --error.
+    #[arg(long)]
+    pub log_dir: Option<std::path::PathBuf>,
+    /// The maximum job array size - read from "scontrol show config" by default
+    ///
+    /// Argument files with more lines than this are submitted as several job arrays, each starting
+    /// after the previous one ends so that --max-tasks holds across all of them.
+    #[arg(long)]
+    pub max_array_size: Option<usize>,
+    /// Print the image, sbatch arguments, and batch script instead of submitting
+    #[arg(long)]
+    pub dry_run: bool,
+}
+
+impl SubmitOptions {
+    /// --sbatch-args followed by each --sbatch-arg.
+    pub fn sbatch_args(&self) -> anyhow::Result<Vec<String>> {
+        split_words(self.sbatch_args.as_deref(), self.sbatch_arg.clone())
+            .context("Unable to parse --sbatch-args")
+    }
+
+    /// --podman-args followed by each --podman-arg.
+    pub fn podman_args(&self) -> anyhow::Result<Vec<String>> {
+        split_words(self.podman_args.as_deref(), self.podman_arg.clone())
+            .context("Unable to parse --podman-args")
+    }
+}
+
+/// Everything needed to render the batch script of a job array.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct JobSpec {
+    #[serde(flatten)]
+    pub container: ContainerSpec,
+    #[serde(default)]
+    pub sbatch_options: SbatchOptions,
+    /// Additional sbatch args from the user
+    #[serde(deserialize_with = "deserialize_words")]
+    pub sbatch_args: Vec<String>,
+    pub max_tasks: i32,
+    /// GPUs allocated to each task
+    #[serde(default)]
+    pub gpus: Option<GpuRequest>,
+    /// Earlier submissions to start after
+    #[serde(default)]
+    pub after: Vec<After>,
+    /// Environment variable names, one per argument in each row
+    pub header: Option<Vec<String>>,
+    /// The arguments of the command, filled in from each row - each row is appended when none
+    #[serde(default)]
+    pub template: Option<Template>,
+    /// The number of each row among the arguments first submitted, from 1 - one more than its
+    /// index when none, and kept by retries
+    #[serde(default)]
+    pub row_numbers: Option<Vec<usize>>,
+    /// Each submission writes its logs to a directory named after its first job ID in here
+    #[serde(default)]
+    pub log_dir: Option<std::path::PathBuf>,
+    /// Arguments of each task
+    pub rows: Vec<Vec<String>>,
+}
+
+impl JobSpec {
+    /// The number of the row `row` among the arguments first submitted, from 1.
+    pub fn row_number(&self, row: usize) -> usize {
+        match &self.row_numbers {
+            Some(row_numbers) => row_numbers[row],
+            None => row + 1,
+        }
+    }
+
+    /// Where logs go without --log-dir: ~/hpc-logs/JOB_NAME, where JOB_NAME is --job-name or the
+    /// name of the command without its extension. None when --output or --error place the logs.
+    pub fn default_log_dir(&self) -> anyhow::Result<Option<std::path::PathBuf>> {
+        if self.sbatch_options.output.is_some() || self.sbatch_options.error.is_some() {
+            return Ok(None);
+        }
+        let job_name = match &self.sbatch_options.job_name {
+            Some(job_name) => job_name.clone(),
+            None => Path::new(&self.container.command)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+        let home = std::env::var_os("HOME").context("HOME is not set")?;
+        Ok(Some(
+            Path::new(&home).join("hpc-logs").join(log_name(&job_name)),
+        ))
+    }
+
+    /// The sbatch dependency of the job array running the rows `first_row` onwards. Arrays after
+    /// the first also start after the `previous` array ends, on top of everything the first
+    /// waits on - the previous array ending says nothing of whether that was satisfied.
+    pub fn dependency(
+        &self,
+        first_row: usize,
+        previous: Option<&str>,
+    ) -> anyhow::Result<Option<String>> {
+        let dependency = self.sbatch_options.dependency.as_deref();
+        let dependency = match (previous, dependency) {
+            (Some(_), Some(dependency)) if dependency.contains('?') => {
+                return Err(anyhow!(
+                    "--dependency with \"?\", any of several dependencies, cannot be combined \
+                     with the dependency of each job array on the previous one - raise \
+                     --max-array-size to submit a single job array"
+                ));
+            }
+            (Some(job_id), Some(dependency)) => Some(format!("afterany:{job_id},{dependency}")),
+            (Some(job_id), None) => Some(format!("afterany:{job_id}")),
+            (None, dependency) => dependency.map(str::to_string),
+        };
+        dependency::combine(dependency.as_deref(), &self.after, first_row)
+    }
+
+    /// Renders the batch script of a job array with one task per row, starting at the row
+    /// `first_row` of the spec, with `dependency` in place of --dependency.
+    pub fn batch_script(
+        &self,
+        site: &Site,
+        rows: &[Vec<String>],
+        first_row: usize,
+        dependency: Option<String>,
+    ) -> BatchScript {
+        let mut sbatch_args = vec![format!("--array=0-{}%{}", rows.len() - 1, self.max_tasks)];
+        sbatch_args.extend(self.sbatch_args(dependency));
+        BatchScript::new(
+            site,
+            sbatch_args,
+            &self.task_script(site, rows, first_row, true),
+        )
+    }
+
+    /// Renders the batch script of a single job - not a job array - running the container once
+    /// with the arguments of the spec's only row.
+    pub fn job_script(&self, site: &Site, dependency: Option<String>) -> BatchScript {
+        let row = &self.rows[0];
+        let args = match &self.template {
+            Some(template) => template.expand(row, self.row_number(0), 0),
+            None => row.clone(),
+        };
+        BatchScript::new(
+            site,
+            self.sbatch_args(dependency),
+            &format!(
+                "{}srun --ntasks=1 {}",
+                self.container.gpu_setup(),
+                self.container.podman_run(site, "", &quote_words(&args))
+            ),
+        )
+    }
+
+    /// The sbatch args of each job, later args overriding earlier ones: the GPUs, the image's
+    /// resources, the sbatch options with `dependency`, and the user's args.
+    fn sbatch_args(&self, dependency: Option<String>) -> Vec<String> {
+        let mut sbatch_args = Vec::new();
+        if let Some(gpus) = &self.gpus {
+            sbatch_args.push(format!("--gres={}", gpus.gres()));
+        }
+        sbatch_args.extend(self.container.image_sbatch_args.iter().cloned());
+        let sbatch_options = SbatchOptions {
+            dependency,
+            ..self.sbatch_options.clone()
+        };
+        sbatch_args.extend(sbatch_options.args());
+        sbatch_args.extend(self.sbatch_args.iter().cloned());
+        sbatch_args
+    }
+
+    /// Renders the bash lines that run the task of `rows` numbered SLURM_ARRAY_TASK_ID, starting
+    /// its container as a job step with `srun` or, outside of Slurm, directly. `rows` start at
+    /// the row `first_row` of the spec.
+    pub fn task_script(
+        &self,
+        site: &Site,
+        rows: &[Vec<String>],
+        first_row: usize,
+        srun: bool,
+    ) -> String {
+        let task_env = match &self.header {
+            Some(header) => format!(
+                "ARG_NAMES=({names})
+TASK_ENV=()
+for i in \"${{!ARG_NAMES[@]}}\"; do
+    TASK_ENV+=(-e \"${{ARG_NAMES[i]}}=${{TASK_ARGS[i]}}\")
+done
+",
+                names = header.join(" ")
+            ),
+            None => "".to_string(),
+        };
+        let task_env_args = if self.header.is_some() {
+            "\"${TASK_ENV[@]}\""
+        } else {
+            ""
+        };
+        // Templates are filled in here, so that each task only picks out its own words
+        let (task_command, command_args) = match &self.template {
+            Some(template) => {
+                let commands = rows
+                    .iter()
+                    .enumerate()
+                    .map(|(index, row)| {
+                        template.expand(row, self.row_number(first_row + index), index)
+                    })
+                    .collect::<Vec<_>>();
+                (
+                    format!(
+                        "WORD_COUNT={word_count}
+COMMAND_WORDS=(
+{command_words}
+)
+TASK_COMMAND=(\"${{COMMAND_WORDS[@]:SLURM_ARRAY_TASK_ID*WORD_COUNT:WORD_COUNT}}\")
+",
+                        word_count = commands[0].len(),
+                        command_words = command_args_script(&commands),
+                    ),
+                    "\"${TASK_COMMAND[@]}\"",
+                )
+            }
+            None => ("".to_string(), "\"${TASK_ARGS[@]}\""),
+        };
+        format!(
+            "ARG_COUNT={arg_count}
+COMMAND_ARGS=(
+{command_args}
+)
+TASK_ARGS=(\"${{COMMAND_ARGS[@]:SLURM_ARRAY_TASK_ID*ARG_COUNT:ARG_COUNT}}\")
+{task_env}{task_command}{gpu_setup}{srun}{podman_run}",
+            arg_count = rows[0].len(),
+            command_args = command_args_script(rows),
+            gpu_setup = self.container.gpu_setup(),
+            srun = if srun { "srun --ntasks=1 " } else { "" },
+            podman_run = self.container.podman_run(site, task_env_args, command_args),
+        )
+    }
+}
+
+/// Submits one or more job arrays covering every row of `spec` and records them. Arrays larger
+/// than the cluster's maximum array size are split, each starting after the previous one ends.
+pub fn submit(
+    site: &Site,
+    spec: JobSpec,
+    max_array_size: Option<usize>,
+    dry_run: bool,
+) -> anyhow::Result<ExitStatus> {
+    let script_sha256 = manifest::script_sha256(&spec)?;
+    let (status, arrays) = submit_arrays(site, &spec, max_array_size, dry_run)?;
+    if !dry_run && !arrays.is_empty() {
+        let manifest = manifest::Manifest::new(spec, arrays, script_sha256);
+        if let Err(what) = manifest.save() {
+            eprintln!("WARN: Unable to record submission: {what:#}");
+        }
+    }
+    Ok(status)
+}
+
+/// Submits the job arrays of `spec` like `submit`, without recording them. Returns the arrays that
+/// were submitted before any sbatch failure, or with --dry-run the arrays that would be, with
+/// placeholders for their job IDs.
+pub fn submit_arrays(
+    site: &Site,
+    spec: &JobSpec,
+    max_array_size: Option<usize>,
+    dry_run: bool,
+) -> anyhow::Result<(ExitStatus, Vec<manifest::SubmittedArray>)> {
+    let max_array_size = match max_array_size {
+        Some(size) => size,
+        None => cluster_max_array_size(),
+    };
+    if dry_run {
+        println!("image: {}", spec.container.image_reference());
+        if let Some(log_dir) = &spec.log_dir {
+            println!("logs: {}", log_dir.join("<job ID>").display());
+        }
+    } else if let Some(log_dir) = &spec.log_dir {
+        std::fs::create_dir_all(log_dir)
+            .with_context(|| format!("Unable to create log directory {log_dir:?}"))?;
+    }
+    let chunk_size = max_array_size.max(1);
+    let chunks = spec.rows.chunks(chunk_size).collect::<Vec<_>>();
+    // Dependencies that cannot be met fail before anything is submitted
+    for chunk_index in 0..chunks.len() {
+        let previous = (chunk_index > 0).then_some("<previous>");
+        spec.dependency(chunk_index * chunk_size, previous)?;
+    }
+    let mut previous_job_id: Option<String> = None;
+    let mut first_job_id: Option<String> = None;
+    let mut arrays = Vec::<manifest::SubmittedArray>::new();
+    let mut status = ExitStatus::default();
+    for (chunk_index, rows) in chunks.iter().enumerate() {
+        let first_row = chunk_index * chunk_size;
+        let dependency = spec.dependency(first_row, previous_job_id.as_deref())?;
+        let mut batch_script = spec.batch_script(site, rows, first_row, dependency);
+        if let Some(log_dir) = &spec.log_dir {
+            // The directory of the first array is created once its job ID is known, so the
+            // array is held until then
+            let dir = match &first_job_id {
+                Some(job_id) => log_dir.join(job_id),
+                None => {
+                    batch_script.sbatch_args.push("--hold".to_string());
+                    log_dir.join("%A")
+                }
+            };
+            for (option, extension) in [("output", "out"), ("error", "err")] {
+                batch_script.sbatch_args.push(format!(
+                    "--{option}={}",
+                    dir.join(format!("%A_%a.{extension}")).display()
+                ));
+            }
+        }
+        if dry_run {
+            println!("{batch_script}");
+            let job_id = format!("<job ID of array {}>", chunk_index + 1);
+            arrays.push(manifest::SubmittedArray {
+                job_id: job_id.clone(),
+                first_row,
+                len: rows.len(),
+            });
+            first_job_id.get_or_insert_with(|| job_id.clone());
+            previous_job_id = Some(job_id);
+            continue;
+        }
+        let output = slurm::sbatch(&batch_script.sbatch_args, &batch_script.script)?;
+        if !output.status.success() {
+            status = output.status;
+            break;
+        }
+        let job_id = slurm::parse_job_id(&output.stdout)?;
+        if let Some(log_dir) = &spec.log_dir
+            && first_job_id.is_none()
+        {
+            let dir = log_dir.join(&job_id);
+            if let Err(what) = std::fs::create_dir(&dir) {
+                if let Err(what) = slurm::cancel(&job_id) {
+                    eprintln!("WARN: {what:#}");
+                }
+                return Err(anyhow::Error::from(what).context(format!(
+                    "Unable to create log directory {dir:?}, cancelled job {job_id}"
+                )));
+            }
+            // A job left held would never run, and without its ID printed nobody would notice
+            if let Err(what) = slurm::release(&job_id) {
+                if let Err(what) = slurm::cancel(&job_id) {
+                    eprintln!("WARN: {what:#}");
+                }
+                let _ = std::fs::remove_dir(&dir);
+                return Err(what.context(format!("Unable to release job {job_id}, cancelled it")));
+            }
+        }
+        if chunks.len() == 1 {
+            println!("Submitted batch job {job_id}");
+        } else {
+            println!(
+                "Submitted batch job {job_id} (arguments {}-{})",
+                first_row + 1,
+                first_row + rows.len()
+            );
+        }
+        arrays.push(manifest::SubmittedArray {
+            job_id: job_id.clone(),
+            first_row,
+            len: rows.len(),
+        });
+        first_job_id.get_or_insert_with(|| job_id.clone());
+        previous_job_id = Some(job_id);
+    }
+    if !dry_run && let (Some(log_dir), Some(first)) = (&spec.log_dir, arrays.first()) {
+        let dir = log_dir.join(&first.job_id);
+        match index_logs(&dir, &spec.rows, &arrays) {
+            Ok(()) => println!("Logs are in {}", dir.display()),
+            Err(what) => eprintln!("WARN: Unable to index the logs in {dir:?}: {what:#}"),
+        }
+    }
+    Ok((status, arrays))
+}
+
+/// The cluster's maximum job array size, or Slurm's default when it cannot be read.
+pub fn cluster_max_array_size() -> usize {
+    slurm::max_array_size().unwrap_or_else(|what| {
+        eprintln!(
+            "WARN: {what:#}, assuming Slurm's default of {}",
+            slurm::DEFAULT_MAX_ARRAY_SIZE
+        );
+        slurm::DEFAULT_MAX_ARRAY_SIZE
+    })
+}
+
+#[test]
+fn reads_specs_with_unsplit_args() {
+    let spec: JobSpec = serde_json::from_str(
+        r#"{
+  "image": "docker.io/library/ubuntu:22.04",
+  "image_digest": null,
+  "image_podman_args": [],
+  "image_sbatch_args": [],
+  "command": "echo",
+  "mounted_script": null,
+  "podman_args": null,
+  "sbatch_args": "--qos=high --comment='two words'",
+  "max_tasks": 16,
+  "header": null,
+  "rows": [["a"]]
+}"#,
+    )
+    .unwrap();
+    assert!(spec.container.podman_args.is_empty());
+    assert_eq!(vec!["--qos=high", "--comment=two words"], spec.sbatch_args);
+    let site = crate::site::built_in();
+    let batch_script = spec.batch_script(&site, &spec.rows, 0, None);
+    assert!(
+        batch_script
+            .script
+            .contains("\nsrun --ntasks=1 podman run --rm")
+    );
+    assert!(
+        spec.task_script(&site, &spec.rows, 0, false)
+            .contains("\npodman run --rm")
+    );
+}
+
+/// Writes index.tsv, mapping each task to its log files and arguments, and links named after the
+/// first argument of each task, e.g. "M68123456.out", to its log files.
+pub(crate) fn index_logs(
+    dir: &Path,
+    rows: &[Vec<String>],
+    arrays: &[manifest::SubmittedArray],
+) -> anyhow::Result<()> {
+    let mut index = "TASK\tOUTPUT\tERROR\tARGUMENTS\n".to_string();
+    let mut names = std::collections::BTreeSet::new();
+    for array in arrays {
+        for task_index in 0..array.len {
+            let task = format!("{}_{task_index}", array.job_id);
+            let row = &rows[array.first_row + task_index];
+            index += &format!(
+                "{task}\t{task}.out\t{task}.err\t{}\n",
+                quote_words(row).replace(['\t', '\n'], " ")
+            );
+            let mut name = log_name(&row[0]);
+            if name.is_empty() {
+                continue;
+            }
+            if !names.insert(name.clone()) {
+                name = format!("{name}_{task}");
+            }
+            for extension in ["out", "err"] {
+                std::os::unix::fs::symlink(
+                    format!("{task}.{extension}"),
+                    dir.join(format!("{name}.{extension}")),
+                )?;
+            }
+        }
+    }
+    std::fs::write(dir.join("index.tsv"), index)?;
+    Ok(())
+}
+
+/// Turns an argument, e.g. a subject ID or a path, into a file name.
+fn log_name(arg: &str) -> String {
+    let name = arg
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.+=@,".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(100)
+        .collect::<String>();
+    name.trim_start_matches('.').to_string()
+}
+
+#[test]
+fn names_logs_after_arguments() {
+    assert_eq!("M68123456", log_name("M68123456"));
+    assert_eq!("_mnt_data_sub-01_ses_1", log_name("/mnt/data/sub-01/ses 1"));
+    assert_eq!("", log_name(".."));
+}
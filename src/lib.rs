@@ -0,0 +1,29 @@
+//! Building blocks for running podman containers as Slurm jobs on IHN's HPC cluster, shared by
+//! the ihn-hpc-sbatch-array and ihn-hpc-sbatch-gpu front-ends.
+//!
+//! A [`script::ContainerSpec`] resolves an image and command into a container, a
+//! [`job::JobSpec`] runs that container once per row of arguments as a job array, and
+//! [`job::submit`] renders its [`script::BatchScript`]s and submits them with sbatch.
+
+pub mod argfile;
+pub mod cache;
+pub mod catalog;
+pub mod dependency;
+pub mod digest;
+pub mod gpu;
+pub mod job;
+pub mod local;
+pub mod manifest;
+pub mod pipeline;
+pub mod sbatch;
+pub mod script;
+pub mod site;
+pub mod slurm;
+pub mod table;
+pub mod template;
+
+/// IHN_HPC_SBATCH_ARRAY_VERSION at build time, recorded in each manifest
+pub const VERSION: &str = match option_env!("IHN_HPC_SBATCH_ARRAY_VERSION") {
+    Some(version) => version,
+    None => "debug",
+};
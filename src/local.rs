@@ -0,0 +1,168 @@
+//! Running a job array on the current machine instead of through Slurm, e.g. to try a script on a
+//! couple of arguments before it takes queue time.
+
+use anyhow::{Context, anyhow};
+use std::{
+    path::Path,
+    process::{Command, ExitStatus},
+    sync::{
+        Mutex,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    job::{JobSpec, index_logs},
+    manifest::SubmittedArray,
+    script::{BatchScript, quote_words},
+    site::Site,
+};
+
+/// Runs every task of `spec` on this machine, up to `spec.max_tasks` at a time. Each task runs the
+/// lines a Slurm task would with SLURM_ARRAY_TASK_ID set, except that its container is started by
+/// podman directly instead of srun. Logs are written to a directory named like a job ID,
+/// "local-SECONDS", in the log directory of `spec`, along with the exit code of each task.
+///
+/// Returns the exit status of the first task that failed, if any.
+pub fn run(site: &Site, spec: &JobSpec, dry_run: bool) -> anyhow::Result<ExitStatus> {
+    let log_dir = spec.log_dir.as_ref().ok_or_else(|| {
+        anyhow!("--backend local writes logs to --log-dir, not to --output or --error")
+    })?;
+    // The same preamble as a batch script, so that tasks see the same TMPDIR on either backend
+    let script = BatchScript::new(
+        site,
+        Vec::new(),
+        &spec.task_script(site, &spec.rows, 0, false),
+    )
+    .script;
+    if dry_run {
+        println!("image: {}", spec.container.image_reference());
+        println!("logs: {}", log_dir.join("local-<time>").display());
+        println!("{script}");
+        return Ok(ExitStatus::default());
+    }
+    let job_id = format!(
+        "local-{}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs()
+    );
+    let dir = log_dir.join(&job_id);
+    std::fs::create_dir_all(log_dir)
+        .and_then(|()| std::fs::create_dir(&dir))
+        .with_context(|| format!("Unable to create log directory {dir:?}"))?;
+    let script_path = dir.join("task.sh");
+    std::fs::write(&script_path, &script)
+        .with_context(|| format!("Unable to write {script_path:?}"))?;
+    let array = SubmittedArray {
+        job_id: job_id.clone(),
+        first_row: 0,
+        len: spec.rows.len(),
+    };
+    if let Err(what) = index_logs(&dir, &spec.rows, &[array]) {
+        eprintln!("WARN: Unable to index the logs in {dir:?}: {what:#}");
+    }
+    println!(
+        "Running {} tasks locally, logs are in {}",
+        spec.rows.len(),
+        dir.display()
+    );
+
+    let next_task = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; spec.rows.len()]);
+    let workers = usize::try_from(spec.max_tasks)
+        .unwrap_or_default()
+        .clamp(1, spec.rows.len().max(1));
+    std::thread::scope(|scope| {
+        let workers = (0..workers)
+            .map(|_| {
+                scope.spawn(|| -> anyhow::Result<()> {
+                    loop {
+                        let task = next_task.fetch_add(1, Ordering::Relaxed);
+                        if task >= spec.rows.len() {
+                            return Ok(());
+                        }
+                        let started = Instant::now();
+                        let status = run_task(&script_path, &dir, &job_id, task)?;
+                        let elapsed = Duration::from_secs(started.elapsed().as_secs());
+                        println!(
+                            "Task {task} ({}) {} after {}",
+                            quote_words(&spec.rows[task]),
+                            describe(status),
+                            humantime::format_duration(elapsed)
+                        );
+                        results.lock().unwrap()[task] = Some((status, elapsed));
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .try_for_each(|worker| worker.join().expect("task runner panicked"))
+    })?;
+
+    let results = results.into_inner().unwrap();
+    let mut exit_codes = "TASK\tEXIT\tELAPSED\n".to_string();
+    let mut failed = Vec::new();
+    for (task, result) in results.into_iter().enumerate() {
+        let Some((status, elapsed)) = result else {
+            continue;
+        };
+        exit_codes += &format!(
+            "{job_id}_{task}\t{}\t{}\n",
+            exit_code(status),
+            elapsed.as_secs()
+        );
+        if !status.success() {
+            failed.push(status);
+        }
+    }
+    if let Err(what) = std::fs::write(dir.join("exit_codes.tsv"), exit_codes) {
+        eprintln!("WARN: Unable to record exit codes in {dir:?}: {what:#}");
+    }
+    println!("{} of {} tasks failed", failed.len(), spec.rows.len());
+    Ok(failed.first().copied().unwrap_or_default())
+}
+
+/// Runs task `task` of the script at `script_path`, writing its stdout and stderr to
+/// JOBID_TASK.out and JOBID_TASK.err in `dir` like Slurm would.
+fn run_task(
+    script_path: &Path,
+    dir: &Path,
+    job_id: &str,
+    task: usize,
+) -> anyhow::Result<ExitStatus> {
+    let log = |extension: &str| {
+        let path = dir.join(format!("{job_id}_{task}.{extension}"));
+        std::fs::File::create(&path).with_context(|| format!("Unable to create {path:?}"))
+    };
+    Command::new("bash")
+        .arg(script_path)
+        .env("SLURM_ARRAY_TASK_ID", task.to_string())
+        .stdin(std::process::Stdio::null())
+        .stdout(log("out")?)
+        .stderr(log("err")?)
+        .status()
+        .context("Unable to run bash")
+}
+
+/// The exit code of a task as sacct shows it, "CODE:SIGNAL".
+fn exit_code(status: ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+    format!(
+        "{}:{}",
+        status.code().unwrap_or_default(),
+        status.signal().unwrap_or_default()
+    )
+}
+
+fn describe(status: ExitStatus) -> String {
+    use std::os::unix::process::ExitStatusExt;
+    match (status.code(), status.signal()) {
+        (Some(0), _) => "completed".to_string(),
+        (Some(code), _) => format!("failed with exit code {code}"),
+        (None, Some(signal)) => format!("was killed by signal {signal}"),
+        (None, None) => "failed".to_string(),
+    }
+}
@@ -1,17 +1,76 @@
 use anyhow::{Context, anyhow};
 use clap::Parser;
-use std::{io::Write, process::ExitStatus};
+use ihn_hpc_sbatch_array::{
+    VERSION,
+    argfile::read_command_arg_file,
+    cache,
+    catalog::{Catalog, Image, qualified_image_name},
+    dependency::AfterArgs,
+    job::{JobSpec, SubmitOptions, submit},
+    local, manifest, pipeline,
+    sbatch::{SbatchOptions, check_raw_args},
+    script::{ContainerSpec, quote_words},
+    site::Site,
+    slurm,
+    table::{format_kib, print_table},
+    template::parse_command,
+};
+use std::process::ExitStatus;
 
 #[derive(Parser)]
-#[command(version = option_env!("IHN_HPC_SBATCH_ARRAY_VERSION").unwrap_or("debug"), verbatim_doc_comment)]
+#[command(
+    version = VERSION,
+    verbatim_doc_comment,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    subcommand_value_name = "SUBCOMMAND",
+    subcommand_help_heading = "Subcommands"
+)]
 /// A program wrapping Slurm's "sbatch" command tailored for IHN's HPC cluster.
 ///
 /// ihn-hpc-sbatch-array wraps Slurm's "sbatch" command and creates a job array
 /// from a text file. The text file (COMMAND_ARG_PATH) contains one nonempty line
 /// for each job in the array. Each line is trimmed and passed to COMMAND as the
-/// one and only argument. The COMMAND is executed inside a podman container. The
-/// user's home directory (/mnt/home/username/) and shared directory
-/// (/mnt/home/shared/) are mounted inside the container at the same locations.
+/// one and only argument. With --delimiter, each line is instead split into
+/// columns and each column is passed to COMMAND as a separate argument. The
+/// COMMAND is executed inside a podman container. The user's home directory
+/// (/mnt/home/username/) and the site's shared directories (/mnt/home/shared/)
+/// are mounted inside the container at the same locations.
+///
+/// Short-hand image identifiers, their default tags, mounts, environment, and
+/// resources come from a catalog: the built-in one, extended by the site-wide
+/// /mnt/apps/etc/ihn-hpc/images.toml and the user's
+/// ~/.config/ihn-hpc/images.toml. For example:
+///
+/// [images.fmriprep]
+/// name = "docker.io/nipreps/fmriprep"
+/// tag = "24.1.1"
+/// mounts = ["${fs_license}:/opt/freesurfer/license.txt:ro"]
+/// env = { FS_LICENSE = "/opt/freesurfer/license.txt" }
+/// resources = { cpus_per_task = 8, mem = "32G", time = "24:00:00" }
+///
+/// Cluster paths - the scratch directory, shared directories, registry
+/// credentials, site-wide catalog, and the [paths] that catalog mounts refer to
+/// as ${name} - come from a site profile. The built-in profile describes IHN's
+/// cluster. A TOML file named by $IHN_HPC_SITE, or else /etc/ihn-hpc/site.toml,
+/// replaces its settings, e.g.:
+///
+/// scratch = "/scratch/$USER"
+/// shared_mounts = ["/data/shared/"]
+/// [paths]
+/// fs_license = "/opt/licenses/freesurfer.txt"
+///
+/// With --header, the first line of a delimited COMMAND_ARG_PATH names its
+/// columns (e.g. a CSV exported from a spreadsheet). Each column of a job's line
+/// is then also exported inside the container as an environment variable named
+/// after its header, e.g. SUBJECT and SESSION.
+///
+/// The stdout and stderr of each task are written to
+/// ~/hpc-logs/JOB_NAME/JOBID/, next to links named after the task's first
+/// argument, e.g. M68123456.out, and an index.tsv of every task (see --log-dir).
+///
+/// With --backend local, the tasks run on the current machine instead, e.g. to
+/// try a new script on a couple of arguments before it takes queue time.
 ///
 /// EXAMPLE
 /// Given the file "arg.txt" with contents:
@@ -31,50 +90,220 @@ use std::{io::Write, process::ExitStatus};
 /// creates a three element job array each running a freesurfer container that
 /// executes "script.sh". In this case, the script receives an argument
 /// representing the subject ID and calls "recon-all" for the given subject.
+///
+/// With placeholders in COMMAND, each line fills in a template of the command
+/// and its arguments instead of being appended to it, so that many tools run
+/// without a wrapper script, e.g. with a comma-delimited "scans.csv" of subjects
+/// and scans:
+/// $ ihn-hpc-sbatch-array --delimiter comma freesurfer \
+///     'recon-all -s {1} -i /data/{1}/{2}.nii -sd /data/subjects' scans.csv
+///
+/// With --after-ok, --after-any, or --after-corr JOBID, the job arrays start
+/// only after the submission that printed JOBID, e.g. to run post-processing
+/// once FreeSurfer is done:
+/// $ ihn-hpc-sbatch-array freesurfer recon.sh subjects.txt
+/// Submitted batch job 1234
+/// $ ihn-hpc-sbatch-array --after-corr 1234 fmriprep post.sh subjects.txt
+///
+/// Several such steps can be described together in a pipeline file and
+/// submitted with "ihn-hpc-sbatch-array pipeline run pipeline.toml". Every stage
+/// runs over the same argument file, each task starting once the tasks for the
+/// same argument of the stages listed in "after" have completed, e.g.:
+///
+/// args = "subjects.txt"
+/// [stages.recon]
+/// image = "freesurfer"
+/// command = "recon.sh"
+/// resources = { cpus_per_task = 8, mem = "32G", time = "24:00:00" }
+/// [stages.post]
+/// image = "fmriprep"
+/// command = "post.sh"
+/// after = ["recon"]
+///
+/// Every submission is recorded under ~/.local/share/ihn-hpc/jobs/, along with
+/// the image, a hash of any mounted script, and the tool version, so that its
+/// failed tasks can later be resubmitted with "ihn-hpc-sbatch-array retry JOBID"
+/// and its progress listed argument by argument with
+/// "ihn-hpc-sbatch-array status JOBID".
 struct Args {
-    /// The maximum number of simultaneous tasks
-    #[arg(long, default_value_t = 16)]
-    max_tasks: i32,
-    /// Podman image tag - ignored when IMAGE is fully qualified
+    #[command(subcommand)]
+    subcommand: Option<Subcommand>,
+    #[command(flatten)]
+    submit: Option<SubmitArgs>,
+    // Not part of SubmitArgs - clap does not detect an optional flatten containing a flatten
+    #[command(flatten)]
+    options: SubmitOptions,
+    #[command(flatten)]
+    sbatch_options: SbatchOptions,
+    #[command(flatten)]
+    after: AfterArgs,
+}
+
+#[derive(clap::Subcommand)]
+enum Subcommand {
+    /// Resubmit the failed, timed out, and cancelled tasks of a previous submission
+    Retry(RetryArgs),
+    /// List the state of each task of a previous submission alongside its arguments
+    Status(StatusArgs),
+    /// Pull an image into the podman storage of compute nodes ahead of a submission
+    CacheImage(CacheImageArgs),
+    /// List the images cached on compute nodes, marking those missing from some nodes
+    Images(ImagesArgs),
+    /// Remove old or unwanted images from compute nodes
+    PruneImages(PruneImagesArgs),
+    /// Submit pipelines of job arrays over one argument file
+    #[command(subcommand)]
+    Pipeline(PipelineSubcommand),
+}
+
+#[derive(clap::Subcommand)]
+enum PipelineSubcommand {
+    /// Submit every stage of a pipeline file, each after the stages it depends on
+    Run(PipelineRunArgs),
+}
+
+#[derive(clap::Args)]
+struct PipelineRunArgs {
+    /// Run each task by tag instead of pinning it to the digest the tag points to at submission
     #[arg(long)]
-    tag: Option<String>,
-    /// Additional args to sbatch
-    #[arg(long, allow_hyphen_values = true)]
-    sbatch_args: Option<String>,
-    /// Additional args to podman
-    #[arg(long, allow_hyphen_values = true)]
-    podman_args: Option<String>,
+    no_pin: bool,
+    /// The maximum job array size - read from "scontrol show config" by default
+    #[arg(long)]
+    max_array_size: Option<usize>,
+    /// Print the image, sbatch arguments, and batch script of each stage instead of submitting
+    #[arg(long)]
+    dry_run: bool,
+    /// TOML file listing the argument file and each stage
+    pipeline: std::path::PathBuf,
+}
+
+#[derive(clap::Args)]
+struct SubmitArgs {
+    /// Where to run the tasks
+    ///
+    /// "local" runs each task on this machine instead of submitting it, up to --max-tasks at a
+    /// time, with plain "podman run" in place of "srun". Sbatch options are ignored, and the logs
+    /// and exit codes of the tasks are written to a directory named local-SECONDS in --log-dir.
+    #[arg(long, value_enum, default_value = "slurm")]
+    backend: Backend,
     /// Podman image - short-hand identifier or qualified name
     ///
     /// IMAGE specifies the podman image used for each container. A short-hand identifier, e.g.
-    /// "freesurfer", may be used for known images. Otherwise IMAGE is passed directly to
+    /// "freesurfer", may be used for images in the catalog. Otherwise IMAGE is passed directly to
     /// podman-run for each job.
-    #[arg(value_parser = parse_image)]
-    image: Image,
+    image: String,
     /// Command to execute inside each container
     ///
     /// COMMAND specifies the command executed inside each container. If COMMAND has a shell script
     /// extension (.sh) and exists on the host it is treated as a user-defined shell script and
     /// mounted inside each container.
+    ///
+    /// COMMAND may instead be a template of the command and its arguments, split like a shell
+    /// would, with placeholders filled in from each line of COMMAND_ARG_PATH: {} for every
+    /// argument, {1} for the first, {NAME} for the column named NAME by --header, {#} for the
+    /// line's number, and {%} for the task's index in its job array. {1.} drops the extension of
+    /// the argument, {1/} keeps its base name, {1//} its directory, and {1/.} its base name
+    /// without extension.
     command: String,
     /// Path to a plaintext file containing one argument per line - the one
     /// argument passed to COMMAND for each array job
+    ///
+    /// Each line is trimmed of leading and trailing whitespace and blank lines are skipped. The
+    /// rest of the line reaches COMMAND unchanged, quotes, "$", and backslashes included.
     command_arg_path: std::path::PathBuf,
 }
 
-#[derive(Clone)]
-enum Image {
-    Freesurfer,
-    QualifiedName(String),
+#[derive(Clone, Copy, clap::ValueEnum)]
+enum Backend {
+    /// Submit the tasks as Slurm job arrays
+    Slurm,
+    /// Run the tasks on this machine
+    Local,
+}
+
+#[derive(clap::Args)]
+struct CacheImageArgs {
+    /// Podman image tag - ignored when IMAGE is fully qualified
+    #[arg(long)]
+    tag: Option<String>,
+    #[command(flatten)]
+    nodes: NodeSelection,
+    /// Podman image - short-hand identifier or qualified name
+    image: String,
+}
+
+#[derive(clap::Args)]
+struct ImagesArgs {
+    /// Podman image tag - ignored when IMAGE is fully qualified
+    #[arg(long)]
+    tag: Option<String>,
+    #[command(flatten)]
+    nodes: NodeSelection,
+    /// Podman images to list - short-hand identifiers or qualified names, every image by default
+    ///
+    /// A short-hand identifier without --tag matches every tag of the image.
+    images: Vec<String>,
+}
+
+#[derive(clap::Args)]
+#[command(group(clap::ArgGroup::new("criteria").required(true).multiple(true)))]
+struct PruneImagesArgs {
+    /// Remove images created longer ago than this, e.g. 90d
+    #[arg(long, value_parser = humantime::parse_duration, group = "criteria")]
+    older_than: Option<std::time::Duration>,
+    /// Never remove this image - a short-hand identifier or qualified name, may be repeated
+    ///
+    /// A short-hand identifier without a tag keeps every tag of the image, with one such as
+    /// freesurfer:7.4.1 only that tag. Nothing is removed when an image to keep is cached on no
+    /// node. Without --older-than, every image not kept is removed.
+    #[arg(long, value_name = "IMAGE", group = "criteria")]
+    keep: Vec<String>,
+    /// List the images that would be removed without removing them
+    #[arg(long)]
+    dry_run: bool,
+    #[command(flatten)]
+    nodes: NodeSelection,
+}
+
+#[derive(clap::Args)]
+#[group(required = true, multiple = true)]
+struct NodeSelection {
+    /// Compute nodes, e.g. "node[01-04]"
+    #[arg(long)]
+    nodelist: Option<String>,
+    /// Partition of the compute nodes - every available node of it without --nodelist
+    #[arg(long)]
+    partition: Option<String>,
 }
 
-fn parse_image(s: &str) -> anyhow::Result<Image> {
-    match s.to_lowercase().as_str() {
-        "freesurfer" => Ok(Image::Freesurfer),
-        _ => Ok(Image::QualifiedName(s.to_string())),
+impl NodeSelection {
+    fn nodes(&self) -> anyhow::Result<Vec<String>> {
+        match (&self.nodelist, &self.partition) {
+            (Some(nodelist), _) => slurm::expand_nodelist(nodelist),
+            (None, Some(partition)) => slurm::partition_nodes(partition),
+            (None, None) => Err(anyhow!("Either --nodelist or --partition is required")),
+        }
     }
 }
 
+#[derive(clap::Args)]
+struct StatusArgs {
+    /// Job ID printed when the job was submitted
+    job_id: String,
+}
+
+#[derive(clap::Args)]
+struct RetryArgs {
+    /// Job ID printed when the job was submitted
+    job_id: String,
+    /// The maximum job array size - read from "scontrol show config" by default
+    #[arg(long)]
+    max_array_size: Option<usize>,
+    /// Print the image, sbatch arguments, and batch script instead of submitting
+    #[arg(long)]
+    dry_run: bool,
+}
+
 fn main() -> std::process::ExitCode {
     match run() {
         Ok(status) => {
@@ -94,123 +323,300 @@ fn main() -> std::process::ExitCode {
 
 fn run() -> anyhow::Result<ExitStatus> {
     let args = Args::parse();
-    let command_arg_file_contents = std::fs::read_to_string(&args.command_arg_path).with_context(|| {
-        format!(
-            "Unable to read command argument file, {:?}",
-            args.command_arg_path
-        )
-    })?;
-    let command_arg_vec = parse_command_arg_file(&command_arg_file_contents);
-    let (command, command_volume_arg) = {
-        let command_path = std::path::Path::new(&args.command);
-        if command_path.exists() && command_path.extension().is_some_and(|ext| ext == "sh") {
-            let mounted_command_path = std::fs::canonicalize(command_path).with_context(||
-                format!("It looks like \"{command}\" is a shell script, but a canonical path cannot be determined for mounting in each container", command=args.command)
-            )?.to_str().with_context(||format!("\"{command}\" is not valid UTF-8", command=args.command))?.to_string();
-            (
-                mounted_command_path.clone(),
-                format!(
-                    "-v {}:{}",
-                    mounted_command_path.clone(),
-                    mounted_command_path
-                ),
-            )
-        } else {
-            (args.command, "".to_string())
+    let site = Site::load()?;
+    match (args.subcommand, args.submit) {
+        (Some(Subcommand::Retry(args)), _) => retry(&site, args),
+        (Some(Subcommand::Status(args)), _) => status(args),
+        (Some(Subcommand::CacheImage(args)), _) => {
+            let image =
+                qualified_image_name(Catalog::load(&site)?.parse_image(&args.image), args.tag);
+            cache::cache_image(
+                &site,
+                &image,
+                &args.nodes.nodes()?,
+                args.nodes.partition.as_deref(),
+            )?;
+            Ok(ExitStatus::default())
         }
-    };
-    let mut sbatch_command = std::process::Command::new("sbatch");
-    sbatch_command.arg(format!(
-        "--array=0-{}%{}",
-        command_arg_vec.len() - 1,
-        args.max_tasks
-    ));
-    if let Some(args) = args.sbatch_args {
-        sbatch_command.args(args.split_whitespace());
+        (Some(Subcommand::Images(args)), _) => {
+            let catalog = Catalog::load(&site)?;
+            let filters = args
+                .images
+                .iter()
+                .map(|image| image_filter(&catalog, image, args.tag.clone()))
+                .collect::<Vec<_>>();
+            let images =
+                cache::node_images(&site, &args.nodes.nodes()?, args.nodes.partition.as_deref())?;
+            cache::print_inventory(&images, &filters);
+            Ok(ExitStatus::default())
+        }
+        (Some(Subcommand::PruneImages(args)), _) => {
+            let catalog = Catalog::load(&site)?;
+            let keep = args
+                .keep
+                .iter()
+                .map(|image| image_filter(&catalog, image, None))
+                .collect::<Vec<_>>();
+            let partition = args.nodes.partition.as_deref();
+            let images = cache::node_images(&site, &args.nodes.nodes()?, partition)?;
+            cache::prune_images(
+                &site,
+                &images,
+                partition,
+                &keep,
+                args.older_than,
+                args.dry_run,
+            )?;
+            Ok(ExitStatus::default())
+        }
+        (Some(Subcommand::Pipeline(PipelineSubcommand::Run(args))), _) => pipeline::run(
+            &site,
+            &args.pipeline,
+            args.no_pin,
+            args.max_array_size,
+            args.dry_run,
+        ),
+        (None, Some(submit_args)) => submit_new(
+            &site,
+            submit_args,
+            args.options,
+            args.sbatch_options,
+            &args.after,
+        ),
+        (None, None) => Err(anyhow!("IMAGE, COMMAND, and COMMAND_ARG_PATH are required")),
     }
-    let mut sbatch_child = sbatch_command
-        .stdin(std::process::Stdio::piped())
-        .spawn()
-        .context("Unable to invoke sbatch")?;
-    if let Some(mut stdin) = sbatch_child.stdin.take() {
-        writeln!(
-            stdin,
-            "#!/bin/bash
-set -u
-export TMPDIR=/ssd/home/$USER/TEMP
-COMMAND_ARGS=(
-{command_args}
-)
-srun --ntasks=1 podman run --rm \
-    -v \"$HOME\":\"$HOME\" \
-    -e HPC_HOME=\"$HOME\" \
-    -v /mnt/home/shared/:/mnt/home/shared/ \
-    {command_volume_arg} \
-    {additional_podman_args} \
-    --authfile /mnt/apps/etc/auth.json \
-    --entrypoint {command} \
-    {podman_args} \
-    {image} \"${{COMMAND_ARGS[$SLURM_ARRAY_TASK_ID]}}\"",
-            command_args = command_arg_vec.join("\n"),
-            additional_podman_args = podman_args_for_image(&args.image),
-            podman_args = args.podman_args.unwrap_or("".to_string()),
-            image = qualified_image_name(args.image, args.tag)
-        )?;
-    } else {
-        return Err(anyhow!("Unable to take stdin of sbatch"));
+}
+
+/// Resolves an image to match cached images against, e.g. "freesurfer:7.4.1" or "ubuntu".
+/// Short-hand identifiers without a tag match every tag of the image.
+fn image_filter(catalog: &Catalog, image: &str, tag: Option<String>) -> String {
+    // A short-hand identifier may carry its tag, which --tag replaces
+    let (image, tag) = match image.rsplit_once(':') {
+        Some((name, image_tag))
+            if !image_tag.contains('/') && matches!(catalog.parse_image(name), Image::Known(_)) =>
+        {
+            (name, tag.or_else(|| Some(image_tag.to_string())))
+        }
+        _ => (image, tag),
+    };
+    match (catalog.parse_image(image), tag) {
+        (Image::Known(image), None) => image.name,
+        (image, tag) => qualified_image_name(image, tag),
     }
-    Ok(sbatch_child.wait()?)
 }
 
-fn podman_args_for_image(c: &Image) -> &'static str {
-    match c {
-        Image::Freesurfer => {
-            "\
--v /mnt/apps/etc/fs_license.txt:/usr/local/freesurfer/.license:ro \
--v /opt/matlab/runtime/R2019b/v97/:/usr/local/freesurfer/MCRv97 \
--e FS_LICENSE=/usr/local/freesurfer/.license"
+fn submit_new(
+    site: &Site,
+    args: SubmitArgs,
+    options: SubmitOptions,
+    sbatch_options: SbatchOptions,
+    after: &AfterArgs,
+) -> anyhow::Result<ExitStatus> {
+    let command_arg_file =
+        read_command_arg_file(&args.command_arg_path, options.delimiter, options.header)?;
+    let (command, template) = parse_command(&args.command, &command_arg_file)?;
+    let image = Catalog::load(site)?.parse_image(&args.image);
+    command_arg_file.check_image_env(&image)?;
+    let mut container = ContainerSpec::new(image, options.tag.clone(), command)?;
+    container.podman_args = options.podman_args()?;
+    let sbatch_args = options.sbatch_args()?;
+    check_raw_args(
+        &sbatch_args,
+        &sbatch_options,
+        &[
+            (
+                "array",
+                "each line of COMMAND_ARG_PATH is a task, see --max-tasks",
+            ),
+            ("dependency", "use --dependency instead"),
+            ("output", "use --output or --log-dir instead"),
+            ("error", "use --error or --log-dir instead"),
+        ],
+    )?;
+    if !options.no_pin {
+        container
+            .pin(site)
+            .context("Unable to pin the image to a digest (--no-pin runs it by tag)")?;
+    }
+    let mut spec = JobSpec {
+        container,
+        sbatch_options,
+        sbatch_args,
+        max_tasks: options.max_tasks,
+        gpus: None,
+        after: after.resolve(command_arg_file.rows.len()),
+        header: command_arg_file.header,
+        template,
+        row_numbers: None,
+        log_dir: None,
+        rows: command_arg_file.rows,
+    };
+    spec.log_dir = match options.log_dir {
+        Some(log_dir) => Some(log_dir),
+        None => spec.default_log_dir()?,
+    };
+    match args.backend {
+        Backend::Slurm => submit(site, spec, options.max_array_size, options.dry_run),
+        Backend::Local => {
+            if !spec.sbatch_options.args().is_empty() || !spec.sbatch_args.is_empty() {
+                eprintln!("WARN: Sbatch options are ignored by --backend local");
+            }
+            local::run(site, &spec, options.dry_run)
         }
-        Image::QualifiedName(_) => "",
     }
 }
 
-fn qualified_image_name(image: Image, tag: Option<String>) -> String {
-    match image {
-        Image::Freesurfer => format!(
-            "docker.io/freesurfer/freesurfer:{}",
-            tag.unwrap_or_else(|| "7.3.2".to_string())
-        ),
-        Image::QualifiedName(n) => {
-            if let Some(t) = tag {
-                eprintln!("WARN: ignoring tag \"{t}\"");
+/// Tasks in these states are resubmitted by "retry"
+const RETRY_STATES: [&str; 8] = [
+    "BOOT_FAIL",
+    "CANCELLED",
+    "DEADLINE",
+    "FAILED",
+    "NODE_FAIL",
+    "OUT_OF_MEMORY",
+    "PREEMPTED",
+    "TIMEOUT",
+];
+
+fn retry(site: &Site, args: RetryArgs) -> anyhow::Result<ExitStatus> {
+    let manifest = manifest::Manifest::load(&args.job_id)?;
+    let job_ids = manifest
+        .arrays
+        .iter()
+        .map(|array| array.job_id.clone())
+        .collect::<Vec<_>>();
+    let tasks = slurm::array_tasks(&job_ids)?;
+    let mut rows = std::collections::BTreeSet::new();
+    let mut unfinished = 0;
+    for task in &tasks {
+        if RETRY_STATES.contains(&task.state.as_str()) {
+            if let Some(row) = manifest.row_of(&task.job_id, task.index) {
+                rows.insert(row);
             }
-            n
+        } else if task.state != "COMPLETED" {
+            unfinished += 1;
         }
     }
+    if unfinished > 0 {
+        eprintln!("WARN: {unfinished} tasks are still pending or running and will not be retried");
+    }
+    if rows.is_empty() {
+        println!("No tasks to retry");
+        return Ok(ExitStatus::default());
+    }
+    eprintln!(
+        "Retrying {} of {} tasks",
+        rows.len(),
+        manifest.spec.rows.len()
+    );
+    if manifest.script_sha256.is_some()
+        && manifest.script_sha256 != manifest::script_sha256(&manifest.spec)?
+    {
+        eprintln!(
+            "WARN: {} has changed since job {} was submitted",
+            manifest
+                .spec
+                .container
+                .mounted_script
+                .as_deref()
+                .unwrap_or_default(),
+            args.job_id
+        );
+    }
+    let mut spec = manifest.spec;
+    spec.row_numbers = Some(rows.iter().map(|&row| spec.row_number(row)).collect());
+    spec.rows = rows.into_iter().map(|row| spec.rows[row].clone()).collect();
+    // What the submission waited on has ended, so waiting on it again may never be satisfied, and
+    // --after-corr would pair the retried tasks with other tasks than the ones they first waited on
+    if let Some(dependency) = spec.sbatch_options.dependency.take() {
+        eprintln!(
+            "WARN: --dependency={dependency} of job {} is not retried",
+            args.job_id
+        );
+    }
+    spec.after.clear();
+    submit(site, spec, args.max_array_size, args.dry_run)
 }
 
-fn parse_command_arg_file(contents: &str) -> Vec<String> {
-    contents
-        .lines()
-        .map(str::trim)
-        .filter(|line| !line.is_empty())
-        .map(|line| format!("\"{}\"", line))
-        .collect::<Vec<_>>()
+fn status(args: StatusArgs) -> anyhow::Result<ExitStatus> {
+    let manifest = manifest::Manifest::load(&args.job_id)
+        .inspect_err(|what| eprintln!("WARN: {what:#}, arguments are not shown"))
+        .ok();
+    let job_ids = match &manifest {
+        Some(manifest) => manifest
+            .arrays
+            .iter()
+            .map(|array| array.job_id.clone())
+            .collect(),
+        None => vec![args.job_id],
+    };
+    let mut tasks = slurm::array_tasks(&job_ids)?;
+    tasks.sort_by_key(|task| {
+        (
+            job_ids.iter().position(|job_id| *job_id == task.job_id),
+            task.index,
+        )
+    });
+    let header = [
+        "TASK",
+        "ARGUMENTS",
+        "STATE",
+        "EXIT",
+        "ELAPSED",
+        "NODE",
+        "MAXRSS",
+    ];
+    let mut table = vec![header.map(str::to_string).to_vec()];
+    let mut totals = std::collections::BTreeMap::<&str, usize>::new();
+    for task in &tasks {
+        let arguments = manifest
+            .as_ref()
+            .and_then(|manifest| {
+                let row = manifest.row_of(&task.job_id, task.index)?;
+                manifest.spec.rows.get(row)
+            })
+            .map(|row| quote_words(row))
+            .unwrap_or_default();
+        table.push(vec![
+            format!("{}_{}", task.job_id, task.index),
+            arguments,
+            task.state.clone(),
+            task.exit_code.clone(),
+            task.elapsed.clone(),
+            task.node_list.clone(),
+            task.max_rss_kib.map(format_kib).unwrap_or_default(),
+        ]);
+        *totals.entry(&task.state).or_default() += 1;
+    }
+    print_table(&table);
+    println!();
+    for (state, count) in totals {
+        println!("{state}: {count}");
+    }
+    println!("TOTAL: {}", tasks.len());
+    Ok(ExitStatus::default())
 }
 
 #[test]
-fn parses_command_arg_file() {
-    assert_eq!(
-        vec!["\"a\"", "\"b\"", "\"c\""],
-        parse_command_arg_file(
-            "
-     a
-
-     b
-
-c
-
-"
-        )
+fn resolves_image_filters() {
+    let catalog = Catalog::parse(
+        "[images.freesurfer]\nname = \"docker.io/freesurfer/freesurfer\"\ntag = \"7.3.2\"",
     )
+    .unwrap();
+    let filter =
+        |image: &str, tag: Option<&str>| image_filter(&catalog, image, tag.map(str::to_string));
+    assert_eq!(
+        "docker.io/freesurfer/freesurfer",
+        filter("freesurfer", None)
+    );
+    assert_eq!(
+        "docker.io/freesurfer/freesurfer:7.4.1",
+        filter("freesurfer:7.4.1", None)
+    );
+    assert_eq!(
+        "docker.io/freesurfer/freesurfer:7.4.1",
+        filter("FreeSurfer", Some("7.4.1"))
+    );
+    assert_eq!("ubuntu:22.04", filter("ubuntu:22.04", None));
+    assert_eq!("localhost:5000/tool", filter("localhost:5000/tool", None));
 }
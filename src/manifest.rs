@@ -0,0 +1,190 @@
+//! Records of submissions under $XDG_DATA_HOME/ihn-hpc/jobs.
+
+use anyhow::{Context, anyhow};
+
+use crate::job::JobSpec;
+use sha2::Digest;
+
+/// A record of a submission, saved for provenance and so that its tasks can later be retried.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct Manifest {
+    pub spec: JobSpec,
+    /// The job arrays the rows of `spec` were split across
+    pub arrays: Vec<SubmittedArray>,
+    /// SHA-256 of the mounted script when it was submitted
+    #[serde(default)]
+    pub script_sha256: Option<String>,
+    /// IHN_HPC_SBATCH_ARRAY_VERSION of the submitting tool
+    #[serde(default)]
+    pub version: String,
+    /// The directory the job was submitted from
+    #[serde(default)]
+    pub submit_dir: std::path::PathBuf,
+    /// RFC 3339 UTC timestamp
+    #[serde(default)]
+    pub submitted_at: String,
+}
+
+/// A record of a pipeline submission - the submission of each stage and the stages it waited on.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PipelineManifest {
+    /// The pipeline file
+    pub pipeline: std::path::PathBuf,
+    /// In the order they were submitted
+    pub stages: Vec<PipelineStage>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct PipelineStage {
+    pub name: String,
+    /// Stages whose corresponding tasks this stage's tasks waited on
+    pub after: Vec<String>,
+    /// Stages whose every task this stage waited on
+    pub after_all: Vec<String>,
+    pub manifest: Manifest,
+}
+
+#[derive(Clone, serde::Serialize, serde::Deserialize)]
+pub struct SubmittedArray {
+    pub job_id: String,
+    /// The row of task 0
+    pub first_row: usize,
+    pub len: usize,
+}
+
+impl Manifest {
+    /// Records a submission made now from the current directory.
+    pub fn new(
+        spec: JobSpec,
+        arrays: Vec<SubmittedArray>,
+        script_sha256: Option<String>,
+    ) -> Manifest {
+        Manifest {
+            spec,
+            arrays,
+            script_sha256,
+            version: crate::VERSION.to_string(),
+            submit_dir: std::env::current_dir().unwrap_or_default(),
+            submitted_at: humantime::format_rfc3339_seconds(std::time::SystemTime::now())
+                .to_string(),
+        }
+    }
+
+    /// Saves the manifest as "<first job ID>.json" in the jobs directory.
+    pub fn save(&self) -> anyhow::Result<std::path::PathBuf> {
+        let first = self
+            .arrays
+            .first()
+            .ok_or_else(|| anyhow!("No job arrays were submitted"))?;
+        let dir = jobs_dir()?;
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Unable to create directory {dir:?}"))?;
+        let path = dir.join(format!("{}.json", first.job_id));
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Unable to write {path:?}"))?;
+        Ok(path)
+    }
+
+    /// Loads the manifest of the submission that created the job `job_id`, which may be a stage of
+    /// a pipeline.
+    pub fn load(job_id: &str) -> anyhow::Result<Manifest> {
+        let dir = jobs_dir()?;
+        let path = dir.join(format!("{job_id}.json"));
+        if path.exists() {
+            return read(&path);
+        }
+        // Only the first job array of a split submission names its manifest, and pipelines are
+        // recorded as a whole
+        for entry in std::fs::read_dir(&dir).with_context(|| format!("Unable to read {dir:?}"))? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let manifests = match read(&path) {
+                Ok(manifest) => vec![manifest],
+                Err(_) => read_pipeline(&path)
+                    .map(|pipeline| {
+                        pipeline
+                            .stages
+                            .into_iter()
+                            .map(|stage| stage.manifest)
+                            .collect()
+                    })
+                    .unwrap_or_default(),
+            };
+            if let Some(manifest) = manifests
+                .into_iter()
+                .find(|manifest| manifest.arrays.iter().any(|array| array.job_id == job_id))
+            {
+                return Ok(manifest);
+            }
+        }
+        Err(anyhow!(
+            "No submission of job {job_id} was recorded in {dir:?}"
+        ))
+    }
+
+    /// Maps an array task back to its row in `spec`.
+    pub fn row_of(&self, job_id: &str, index: usize) -> Option<usize> {
+        self.arrays
+            .iter()
+            .find(|array| array.job_id == job_id && index < array.len)
+            .map(|array| array.first_row + index)
+    }
+}
+
+impl PipelineManifest {
+    /// Saves the manifest as "pipeline-<first job ID>.json" in the jobs directory.
+    pub fn save(&self) -> anyhow::Result<std::path::PathBuf> {
+        let first = self
+            .stages
+            .first()
+            .and_then(|stage| stage.manifest.arrays.first())
+            .ok_or_else(|| anyhow!("No job arrays were submitted"))?;
+        let dir = jobs_dir()?;
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("Unable to create directory {dir:?}"))?;
+        let path = dir.join(format!("pipeline-{}.json", first.job_id));
+        std::fs::write(&path, serde_json::to_string_pretty(self)?)
+            .with_context(|| format!("Unable to write {path:?}"))?;
+        Ok(path)
+    }
+}
+
+/// Hashes the current contents of the script mounted by `spec`, if any.
+pub fn script_sha256(spec: &JobSpec) -> anyhow::Result<Option<String>> {
+    let Some(path) = &spec.container.mounted_script else {
+        return Ok(None);
+    };
+    let contents = std::fs::read(path).with_context(|| format!("Unable to read {path:?}"))?;
+    Ok(Some(
+        sha2::Sha256::digest(contents)
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect(),
+    ))
+}
+
+fn read(path: &std::path::Path) -> anyhow::Result<Manifest> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("Unable to read {path:?}"))?;
+    serde_json::from_str(&contents).with_context(|| format!("Unable to parse {path:?}"))
+}
+
+fn read_pipeline(path: &std::path::Path) -> anyhow::Result<PipelineManifest> {
+    let contents =
+        std::fs::read_to_string(path).with_context(|| format!("Unable to read {path:?}"))?;
+    serde_json::from_str(&contents).with_context(|| format!("Unable to parse {path:?}"))
+}
+
+/// $XDG_DATA_HOME/ihn-hpc/jobs, falling back to ~/.local/share/ihn-hpc/jobs
+pub fn jobs_dir() -> anyhow::Result<std::path::PathBuf> {
+    let data_home = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => std::path::PathBuf::from(
+            std::env::var_os("HOME").ok_or_else(|| anyhow!("HOME is not set"))?,
+        )
+        .join(".local/share"),
+    };
+    Ok(data_home.join("ihn-hpc/jobs"))
+}
@@ -0,0 +1,347 @@
+//! Pipelines of job arrays over one argument file. Each stage is a job array whose tasks start
+//! after the tasks for the same arguments of its upstream stages, or after every task of them.
+//!
+//! A pipeline file is TOML, e.g.:
+//!
+//! ```toml
+//! args = "subjects.txt"
+//!
+//! [stages.recon]
+//! image = "freesurfer"
+//! command = "recon.sh"
+//! resources = { cpus_per_task = 8, mem = "32G", time = "24:00:00" }
+//!
+//! [stages.qc]
+//! image = "docker.io/library/python:3.12"
+//! command = "qc.sh"
+//! after = ["recon"]
+//! ```
+
+use anyhow::{Context, anyhow};
+use std::{
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+    process::ExitStatus,
+};
+
+use crate::{
+    argfile::{CommandArgFile, Delimiter, read_command_arg_file},
+    catalog::Catalog,
+    dependency::{After, AfterKind},
+    gpu::GpuRequest,
+    job::{self, JobSpec},
+    manifest::{self, PipelineManifest, PipelineStage},
+    sbatch::{SbatchOptions, check_raw_args},
+    script::{ContainerSpec, deserialize_words},
+    site::Site,
+    slurm,
+    template::parse_command,
+};
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Pipeline {
+    /// The arguments of every stage, one task per line as COMMAND_ARG_PATH - relative to the
+    /// pipeline file
+    pub args: PathBuf,
+    #[serde(default)]
+    pub delimiter: Option<Delimiter>,
+    #[serde(default)]
+    pub header: bool,
+    /// The maximum number of simultaneous tasks of each stage
+    #[serde(default = "default_max_tasks")]
+    pub max_tasks: i32,
+    /// Each stage logs to a directory named after it in here - relative to the pipeline file
+    #[serde(default)]
+    pub log_dir: Option<PathBuf>,
+    pub stages: BTreeMap<String, Stage>,
+    /// The directory of the pipeline file
+    #[serde(skip)]
+    pub dir: PathBuf,
+}
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Stage {
+    /// Short-hand identifier or qualified name, as IMAGE
+    pub image: String,
+    pub tag: Option<String>,
+    /// As COMMAND, which may be a template - shell scripts are relative to the pipeline file
+    pub command: String,
+    /// Sbatch options of each task, named like the options of the same name with "_" for "-",
+    /// e.g. cpus_per_task - the job name defaults to the stage's
+    #[serde(default)]
+    pub resources: SbatchOptions,
+    /// GPUs of each task as MODEL:COUNT, as with ihn-hpc-sbatch-gpu
+    pub gpus: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_words")]
+    pub podman_args: Vec<String>,
+    #[serde(default, deserialize_with = "deserialize_words")]
+    pub sbatch_args: Vec<String>,
+    /// Stages whose task for the same arguments must complete successfully before each task
+    #[serde(default)]
+    pub after: Vec<String>,
+    /// Stages whose every task must complete successfully before any task
+    #[serde(default)]
+    pub after_all: Vec<String>,
+}
+
+fn default_max_tasks() -> i32 {
+    16
+}
+
+impl Pipeline {
+    /// Reads a pipeline file, resolving its paths relative to the file.
+    pub fn load(path: &Path) -> anyhow::Result<Pipeline> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Unable to read {path:?}"))?;
+        let mut pipeline: Pipeline =
+            toml::from_str(&contents).with_context(|| format!("Invalid pipeline {path:?}"))?;
+        pipeline.dir = path.parent().unwrap_or(Path::new("")).to_path_buf();
+        pipeline.args = pipeline.dir.join(&pipeline.args);
+        pipeline.log_dir = pipeline.log_dir.map(|log_dir| pipeline.dir.join(log_dir));
+        Ok(pipeline)
+    }
+
+    /// The stages in an order in which each comes after its upstream stages.
+    pub fn order(&self) -> anyhow::Result<Vec<&str>> {
+        if self.stages.is_empty() {
+            return Err(anyhow!("The pipeline has no stages"));
+        }
+        for (name, stage) in &self.stages {
+            for upstream in stage.after.iter().chain(&stage.after_all) {
+                if !self.stages.contains_key(upstream) {
+                    return Err(anyhow!(
+                        "Stage \"{name}\" comes after \"{upstream}\", which is not a stage"
+                    ));
+                }
+            }
+        }
+        let mut order = Vec::<&str>::new();
+        while order.len() < self.stages.len() {
+            let next = self.stages.iter().find(|(name, stage)| {
+                !order.contains(&name.as_str())
+                    && stage
+                        .after
+                        .iter()
+                        .chain(&stage.after_all)
+                        .all(|upstream| order.contains(&upstream.as_str()))
+            });
+            match next {
+                Some((name, _)) => order.push(name),
+                None => {
+                    let cycle = self
+                        .stages
+                        .keys()
+                        .filter(|name| !order.contains(&name.as_str()))
+                        .map(String::as_str)
+                        .collect::<Vec<_>>();
+                    return Err(anyhow!("Stages {} come after each other", cycle.join(", ")));
+                }
+            }
+        }
+        Ok(order)
+    }
+}
+
+/// Submits every stage of the pipeline at `path`, each after its upstream stages, and records
+/// them together. Every stage is checked before any is submitted.
+pub fn run(
+    site: &Site,
+    path: &Path,
+    no_pin: bool,
+    max_array_size: Option<usize>,
+    dry_run: bool,
+) -> anyhow::Result<ExitStatus> {
+    let pipeline = Pipeline::load(path)?;
+    let order = pipeline.order()?;
+    let command_arg_file =
+        read_command_arg_file(&pipeline.args, pipeline.delimiter, pipeline.header)?;
+    let catalog = Catalog::load(site)?;
+    // Stages are checked against the cluster's GPUs like ihn-hpc-sbatch-gpu checks --gpus
+    let advertised_gpus = if pipeline.stages.values().any(|stage| stage.gpus.is_some()) {
+        slurm::gpu_gres()
+            .inspect_err(|what| eprintln!("WARN: {what:#}, GPUs are not checked"))
+            .ok()
+    } else {
+        None
+    };
+    let mut specs = Vec::new();
+    for name in &order {
+        let stage = &pipeline.stages[*name];
+        let spec = stage_spec(
+            site,
+            &catalog,
+            &pipeline,
+            name,
+            stage,
+            &command_arg_file,
+            no_pin,
+            advertised_gpus.as_deref(),
+        )
+        .with_context(|| format!("Invalid stage \"{name}\""))?;
+        specs.push(spec);
+    }
+    // Every stage is split into job arrays at the same arguments, so that --after-corr holds
+    let max_array_size = max_array_size.unwrap_or_else(job::cluster_max_array_size);
+
+    let mut stages = Vec::<PipelineStage>::new();
+    let mut status = ExitStatus::default();
+    for (name, mut spec) in order.iter().zip(specs) {
+        let stage = &pipeline.stages[*name];
+        let upstream = |names: &[String], kind| {
+            names
+                .iter()
+                .map(|upstream| {
+                    let arrays = &stages
+                        .iter()
+                        .find(|stage| stage.name == *upstream)
+                        .expect("upstream stages are submitted first")
+                        .manifest
+                        .arrays;
+                    After {
+                        kind,
+                        job_id: arrays[0].job_id.clone(),
+                        arrays: arrays.clone(),
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+        spec.after = upstream(&stage.after, AfterKind::Corr);
+        spec.after.extend(upstream(&stage.after_all, AfterKind::Ok));
+        println!("Stage {name}");
+        let script_sha256 = manifest::script_sha256(&spec)?;
+        let (stage_status, mut arrays) =
+            job::submit_arrays(site, &spec, Some(max_array_size), dry_run)?;
+        if dry_run {
+            for (index, array) in arrays.iter_mut().enumerate() {
+                array.job_id = format!("<job ID of {name} array {}>", index + 1);
+            }
+        }
+        let submitted = !arrays.is_empty();
+        if submitted {
+            stages.push(PipelineStage {
+                name: name.to_string(),
+                after: stage.after.clone(),
+                after_all: stage.after_all.clone(),
+                manifest: manifest::Manifest::new(spec, arrays, script_sha256),
+            });
+        }
+        if !stage_status.success() || !submitted {
+            status = stage_status;
+            let rest = &order[stages.len()..];
+            eprintln!("WARN: Stages {} were not submitted", rest.join(", "));
+            break;
+        }
+    }
+    if !dry_run && !stages.is_empty() {
+        let manifest = PipelineManifest {
+            pipeline: std::fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf()),
+            stages,
+        };
+        match manifest.save() {
+            Ok(path) => println!("Recorded pipeline in {}", path.display()),
+            Err(what) => eprintln!("WARN: Unable to record pipeline: {what:#}"),
+        }
+    }
+    Ok(status)
+}
+
+/// The job array of a stage, without what it comes after.
+#[allow(clippy::too_many_arguments)]
+fn stage_spec(
+    site: &Site,
+    catalog: &Catalog,
+    pipeline: &Pipeline,
+    name: &str,
+    stage: &Stage,
+    command_arg_file: &CommandArgFile,
+    no_pin: bool,
+    advertised_gpus: Option<&[GpuRequest]>,
+) -> anyhow::Result<JobSpec> {
+    stage.resources.validate()?;
+    check_raw_args(
+        &stage.sbatch_args,
+        &stage.resources,
+        &[
+            ("array", "each line of args is a task, see max_tasks"),
+            ("dependency", "use after or after_all instead"),
+            ("output", "use log_dir instead"),
+            ("error", "use log_dir instead"),
+            ("gres", "use gpus instead"),
+            ("gpus", "use gpus instead"),
+        ],
+    )?;
+    let image = catalog.parse_image(&stage.image);
+    command_arg_file.check_image_env(&image)?;
+    let (mut command, template) = parse_command(&stage.command, command_arg_file)?;
+    if Path::new(&command)
+        .extension()
+        .is_some_and(|ext| ext == "sh")
+    {
+        command = pipeline.dir.join(&command).to_string_lossy().to_string();
+    }
+    let mut container = ContainerSpec::new(image, stage.tag.clone(), command)?;
+    container.podman_args = stage.podman_args.clone();
+    let gpus = match &stage.gpus {
+        Some(gpus) => {
+            let gpus = gpus.parse::<GpuRequest>()?;
+            if let Some(advertised) = advertised_gpus {
+                gpus.validate(advertised)?;
+            }
+            container.gpu = true;
+            Some(gpus)
+        }
+        None => None,
+    };
+    if !no_pin {
+        container
+            .pin(site)
+            .context("Unable to pin the image to a digest (--no-pin runs it by tag)")?;
+    }
+    let mut sbatch_options = stage.resources.clone();
+    sbatch_options
+        .job_name
+        .get_or_insert_with(|| name.to_string());
+    let mut spec = JobSpec {
+        container,
+        sbatch_options,
+        sbatch_args: stage.sbatch_args.clone(),
+        max_tasks: pipeline.max_tasks,
+        gpus,
+        after: Vec::new(),
+        header: command_arg_file.header.clone(),
+        template,
+        row_numbers: None,
+        log_dir: None,
+        rows: command_arg_file.rows.clone(),
+    };
+    spec.log_dir = match (&pipeline.log_dir, spec.default_log_dir()?) {
+        (Some(log_dir), Some(_)) => Some(log_dir.join(name)),
+        (_, default_log_dir) => default_log_dir,
+    };
+    Ok(spec)
+}
+
+#[test]
+fn orders_stages_after_upstream_stages() {
+    let pipeline = |stages: &str| {
+        toml::from_str::<Pipeline>(&format!("args = \"args.txt\"\n{stages}")).unwrap()
+    };
+    let stage = |name: &str, after: &str| {
+        format!("[stages.{name}]\nimage = \"ubuntu\"\ncommand = \"echo\"\n{after}\n")
+    };
+    let linear = pipeline(
+        &[
+            stage("a", "after = [\"c\"]"),
+            stage("b", ""),
+            stage("c", "after_all = [\"b\"]"),
+        ]
+        .concat(),
+    );
+    assert_eq!(vec!["b", "c", "a"], linear.order().unwrap());
+    let cycle = pipeline(&[stage("a", "after = [\"b\"]"), stage("b", "after = [\"a\"]")].concat());
+    assert!(cycle.order().is_err());
+    let unknown = pipeline(&stage("a", "after = [\"z\"]"));
+    assert!(unknown.order().is_err());
+}
@@ -0,0 +1,443 @@
+//! The sbatch options users commonly set, checked before anything is submitted.
+
+use anyhow::{Context, anyhow};
+
+/// Typed sbatch options. Each is passed to sbatch as the option of the same name.
+#[derive(Clone, Default, clap::Args, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SbatchOptions {
+    /// Slurm partition to run in
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_name)]
+    pub partition: Option<String>,
+    /// Time limit, e.g. "90", "4:00:00", or "2-12"
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_time)]
+    pub time: Option<String>,
+    /// Memory per node, e.g. "16G"
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_mem)]
+    pub mem: Option<String>,
+    /// CPUs of each task
+    #[arg(long, help_heading = "Sbatch options", value_parser = clap::value_parser!(u32).range(1..))]
+    pub cpus_per_task: Option<u32>,
+    /// Name of the job
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_nonempty)]
+    pub job_name: Option<String>,
+    /// File for the job's stdout, e.g. "logs/%x_%A_%a.out" - its directory must exist
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_log_path)]
+    pub output: Option<String>,
+    /// File for the job's stderr - its directory must exist
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_log_path)]
+    pub error: Option<String>,
+    /// Start after other jobs, e.g. "afterok:1234:1235"
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_dependency)]
+    pub dependency: Option<String>,
+    /// Quality of service
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_name)]
+    pub qos: Option<String>,
+    /// Account to charge
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_name)]
+    pub account: Option<String>,
+    /// Nodes not to run on, e.g. "node[01-02]"
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_hostlist)]
+    pub exclude: Option<String>,
+    /// Nodes to run on, e.g. "node[01-02]"
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_hostlist)]
+    pub nodelist: Option<String>,
+    /// Events to send mail about, e.g. "END,FAIL"
+    #[arg(
+        long,
+        help_heading = "Sbatch options",
+        value_enum,
+        value_delimiter = ',',
+        ignore_case = true
+    )]
+    pub mail_type: Vec<MailType>,
+    /// User to send mail to - the submitting user by default
+    #[arg(long, help_heading = "Sbatch options", value_parser = parse_nonempty)]
+    pub mail_user: Option<String>,
+}
+
+/// Slurm's mail types, named as sbatch names them - also in manifests and pipeline files.
+#[derive(Clone, Copy, Debug, PartialEq, clap::ValueEnum, serde::Serialize, serde::Deserialize)]
+#[value(rename_all = "SCREAMING_SNAKE_CASE")]
+#[serde(try_from = "String", into = "String")]
+pub enum MailType {
+    None,
+    Begin,
+    End,
+    Fail,
+    Requeue,
+    All,
+    InvalidDepend,
+    StageOut,
+    TimeLimit,
+    #[value(name = "TIME_LIMIT_90")]
+    TimeLimit90,
+    #[value(name = "TIME_LIMIT_80")]
+    TimeLimit80,
+    #[value(name = "TIME_LIMIT_50")]
+    TimeLimit50,
+    ArrayTasks,
+}
+
+impl MailType {
+    /// The name sbatch takes, e.g. "TIME_LIMIT_90".
+    pub fn name(self) -> String {
+        clap::ValueEnum::to_possible_value(&self)
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default()
+    }
+}
+
+impl From<MailType> for String {
+    fn from(mail_type: MailType) -> String {
+        mail_type.name()
+    }
+}
+
+/// Reads sbatch's names in any case, and the names of manifests that predate them, e.g.
+/// "TimeLimit90".
+impl TryFrom<String> for MailType {
+    type Error = anyhow::Error;
+
+    fn try_from(name: String) -> anyhow::Result<MailType> {
+        let normalize = |name: &str| name.replace('_', "").to_ascii_lowercase();
+        <MailType as clap::ValueEnum>::value_variants()
+            .iter()
+            .find(|mail_type| normalize(&mail_type.name()) == normalize(&name))
+            .copied()
+            .ok_or_else(|| anyhow!("\"{name}\" is not a mail type, e.g. \"END\" or \"FAIL\""))
+    }
+}
+
+impl SbatchOptions {
+    /// Checks options that were not parsed from the command line, e.g. those of a pipeline file.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        type Parser = fn(&str) -> anyhow::Result<String>;
+        let checks: [(&str, &Option<String>, Parser); 12] = [
+            ("partition", &self.partition, parse_name),
+            ("time", &self.time, parse_time),
+            ("mem", &self.mem, parse_mem),
+            ("job_name", &self.job_name, parse_nonempty),
+            ("output", &self.output, parse_log_path),
+            ("error", &self.error, parse_log_path),
+            ("dependency", &self.dependency, parse_dependency),
+            ("qos", &self.qos, parse_name),
+            ("account", &self.account, parse_name),
+            ("exclude", &self.exclude, parse_hostlist),
+            ("nodelist", &self.nodelist, parse_hostlist),
+            ("mail_user", &self.mail_user, parse_nonempty),
+        ];
+        for (name, value, parse) in checks {
+            if let Some(value) = value {
+                parse(value).with_context(|| format!("Invalid {name}"))?;
+            }
+        }
+        if self.cpus_per_task == Some(0) {
+            return Err(anyhow!("cpus_per_task must be at least 1"));
+        }
+        Ok(())
+    }
+
+    /// The options as sbatch args.
+    pub fn args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        let options = [
+            ("partition", &self.partition),
+            ("time", &self.time),
+            ("mem", &self.mem),
+            (
+                "cpus-per-task",
+                &self.cpus_per_task.map(|cpus| cpus.to_string()),
+            ),
+            ("job-name", &self.job_name),
+            ("output", &self.output),
+            ("error", &self.error),
+            ("dependency", &self.dependency),
+            ("qos", &self.qos),
+            ("account", &self.account),
+            ("exclude", &self.exclude),
+            ("nodelist", &self.nodelist),
+        ];
+        for (name, value) in options {
+            if let Some(value) = value {
+                args.push(format!("--{name}={value}"));
+            }
+        }
+        if !self.mail_type.is_empty() {
+            let mail_types = self
+                .mail_type
+                .iter()
+                .map(|mail_type| mail_type.name())
+                .collect::<Vec<_>>();
+            args.push(format!("--mail-type={}", mail_types.join(",")));
+        }
+        if let Some(user) = &self.mail_user {
+            args.push(format!("--mail-user={user}"));
+        }
+        args
+    }
+}
+
+/// Rejects raw sbatch args that set any of `reserved`, long option names that are set by the
+/// tool itself, and any option that is also given as a typed option. Like sbatch, long options
+/// may be abbreviated, e.g. "--arr" for "--array".
+pub fn check_raw_args(
+    raw: &[String],
+    options: &SbatchOptions,
+    reserved: &[(&str, &str)],
+) -> anyhow::Result<()> {
+    let typed = options
+        .args()
+        .iter()
+        .filter_map(|arg| option_name(arg).map(str::to_string))
+        .collect::<Vec<_>>();
+    for arg in raw {
+        let Some(name) = option_name(arg) else {
+            continue;
+        };
+        if let Some((option, instead)) = reserved
+            .iter()
+            .find(|(option, _)| abbreviates(name, option))
+        {
+            return Err(anyhow!(
+                "\"{arg}\" in --sbatch-args would override the --{option} this tool sets - {instead}"
+            ));
+        }
+        if let Some(option) = typed.iter().find(|option| abbreviates(name, option)) {
+            return Err(anyhow!(
+                "--{option} is given both directly and as \"{arg}\" in --sbatch-args"
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether sbatch could read the long option `name` as `option`, which it also accepts by any
+/// prefix that is not ambiguous - ambiguous prefixes fail in sbatch anyway.
+fn abbreviates(name: &str, option: &str) -> bool {
+    !name.is_empty() && option.starts_with(name)
+}
+
+/// The long name of the sbatch option `arg` sets, e.g. "array" for "--array=0-9" or "-a0-9".
+/// Short options are only recognized for options this module knows about.
+fn option_name(arg: &str) -> Option<&str> {
+    if let Some(long) = arg.strip_prefix("--") {
+        return Some(long.split_once('=').map_or(long, |(name, _)| name));
+    }
+    let short = arg.strip_prefix('-')?.chars().next()?;
+    Some(match short {
+        'a' => "array",
+        'd' => "dependency",
+        'G' => "gpus",
+        'p' => "partition",
+        't' => "time",
+        'c' => "cpus-per-task",
+        'J' => "job-name",
+        'o' => "output",
+        'e' => "error",
+        'q' => "qos",
+        'A' => "account",
+        'x' => "exclude",
+        'w' => "nodelist",
+        _ => return None,
+    })
+}
+
+fn parse_nonempty(value: &str) -> anyhow::Result<String> {
+    if value.trim().is_empty() {
+        return Err(anyhow!("must not be empty"));
+    }
+    Ok(value.to_string())
+}
+
+/// Partition, QOS, and account names
+fn parse_name(value: &str) -> anyhow::Result<String> {
+    if value.is_empty()
+        || !value
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c))
+    {
+        return Err(anyhow!("\"{value}\" is not a valid Slurm name"));
+    }
+    Ok(value.to_string())
+}
+
+/// One of Slurm's time formats: "minutes", "minutes:seconds", "hours:minutes:seconds",
+/// "days-hours", "days-hours:minutes", "days-hours:minutes:seconds", or "UNLIMITED".
+fn parse_time(value: &str) -> anyhow::Result<String> {
+    let invalid = || {
+        anyhow!(
+            "\"{value}\" is not a Slurm time, e.g. \"90\" (minutes), \"4:00:00\", or \"2-12:00:00\""
+        )
+    };
+    if matches!(value, "UNLIMITED" | "INFINITE") {
+        return Ok(value.to_string());
+    }
+    let (days, clock) = match value.split_once('-') {
+        Some((days, clock)) => (Some(days), clock),
+        None => (None, value),
+    };
+    let is_number = |part: &str| !part.is_empty() && part.chars().all(|c| c.is_ascii_digit());
+    let parts = clock.split(':').collect::<Vec<_>>();
+    if days.is_some_and(|days| !is_number(days))
+        || parts.len() > 3
+        || !parts.iter().all(|part| is_number(part))
+    {
+        return Err(invalid());
+    }
+    Ok(value.to_string())
+}
+
+/// A size with an optional K, M, G, or T suffix
+fn parse_mem(value: &str) -> anyhow::Result<String> {
+    let number = value.trim_end_matches(['K', 'M', 'G', 'T']);
+    if number.is_empty()
+        || value.len() - number.len() > 1
+        || !number.chars().all(|c| c.is_ascii_digit())
+    {
+        return Err(anyhow!("\"{value}\" is not a memory size, e.g. \"16G\""));
+    }
+    Ok(value.to_string())
+}
+
+/// A path whose directory, unless it contains a filename pattern such as "%j", exists.
+fn parse_log_path(value: &str) -> anyhow::Result<String> {
+    let dir = std::path::Path::new(value)
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty());
+    if let Some(dir) = dir
+        && !dir.to_string_lossy().contains('%')
+        && !dir.is_dir()
+    {
+        return Err(anyhow!(
+            "directory {dir:?} does not exist - Slurm would silently drop the job's output"
+        ));
+    }
+    Ok(value.to_string())
+}
+
+/// Dependencies such as "afterok:1234:1235,afterany:1236" or "singleton"
+fn parse_dependency(value: &str) -> anyhow::Result<String> {
+    for dependency in value.split([',', '?']) {
+        let mut parts = dependency.split(':');
+        let kind = parts.next().unwrap_or_default();
+        let job_ids = parts.collect::<Vec<_>>();
+        let valid = match kind {
+            "singleton" => job_ids.is_empty(),
+            "after" | "afterany" | "afterburstbuffer" | "aftercorr" | "afternotok" | "afterok" => {
+                !job_ids.is_empty()
+                    && job_ids.iter().all(|job_id| {
+                        // Job IDs, optionally with "+minutes" for "after"
+                        let job_id = job_id.split_once('+').map_or(*job_id, |(id, _)| id);
+                        !job_id.is_empty() && job_id.chars().all(|c| c.is_ascii_digit() || c == '_')
+                    })
+            }
+            _ => false,
+        };
+        if !valid {
+            return Err(anyhow!(
+                "\"{dependency}\" is not a Slurm dependency, e.g. \"afterok:1234\""
+            ));
+        }
+    }
+    Ok(value.to_string())
+}
+
+/// A Slurm hostlist such as "node[01-03],gpu1"
+fn parse_hostlist(value: &str) -> anyhow::Result<String> {
+    // Ranges such as "[01-03,07]" may not nest
+    let mut in_range = false;
+    let valid = !value.is_empty()
+        && value.chars().all(|c| match c {
+            '[' if !in_range => {
+                in_range = true;
+                true
+            }
+            ']' if in_range => {
+                in_range = false;
+                true
+            }
+            c => c.is_ascii_alphanumeric() || "-_.,".contains(c),
+        })
+        && !in_range;
+    if !valid {
+        return Err(anyhow!(
+            "\"{value}\" is not a Slurm hostlist, e.g. \"node[01-03]\""
+        ));
+    }
+    Ok(value.to_string())
+}
+
+#[test]
+fn validates_option_formats() {
+    for time in [
+        "90",
+        "30:00",
+        "4:00:00",
+        "2-12",
+        "2-12:30",
+        "2-12:30:00",
+        "UNLIMITED",
+    ] {
+        assert!(parse_time(time).is_ok(), "{time}");
+    }
+    for time in ["", "4h", "1:2:3:4", "-5", "a-1"] {
+        assert!(parse_time(time).is_err(), "{time}");
+    }
+    assert!(parse_mem("16G").is_ok());
+    assert!(parse_mem("16GB").is_err());
+    assert!(parse_dependency("afterok:12:13,afterany:14_2").is_ok());
+    assert!(parse_dependency("afterok").is_err());
+    assert!(parse_hostlist("node[01-03],gpu1").is_ok());
+    assert!(parse_hostlist("node[01-03").is_err());
+}
+
+#[test]
+fn rejects_conflicting_raw_args() {
+    let options = SbatchOptions {
+        partition: Some("gpu".to_string()),
+        ..SbatchOptions::default()
+    };
+    let reserved = [("array", "use --max-tasks instead")];
+    let check = |raw: &[&str]| {
+        let raw = raw.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        check_raw_args(&raw, &options, &reserved)
+    };
+    assert!(check(&["--comment=x", "--qos=high"]).is_ok());
+    assert!(check(&["--array=0-9%2"]).is_err());
+    assert!(check(&["-a", "0-9"]).is_err());
+    assert!(check(&["-p", "cpu"]).is_err());
+    assert!(check(&["--arr=0-9"]).is_err());
+    assert!(check(&["--part", "cpu"]).is_err());
+    assert!(check(&["--arrays=0-9"]).is_ok());
+}
+
+#[test]
+fn passes_mail_types_as_sbatch_names() {
+    let options = SbatchOptions {
+        mail_type: vec![MailType::End, MailType::TimeLimit90, MailType::ArrayTasks],
+        ..SbatchOptions::default()
+    };
+    assert_eq!(
+        vec!["--mail-type=END,TIME_LIMIT_90,ARRAY_TASKS"],
+        options.args()
+    );
+    assert_eq!(
+        Ok(MailType::TimeLimit80),
+        <MailType as clap::ValueEnum>::from_str("time_limit_80", true)
+    );
+    let read = |json: &str| serde_json::from_str::<Vec<MailType>>(json).ok();
+    assert_eq!(
+        Some(vec![MailType::TimeLimit50, MailType::Fail]),
+        read(r#"["TIME_LIMIT_50", "Fail"]"#)
+    );
+    assert_eq!(
+        Some(vec![MailType::TimeLimit90]),
+        read(r#"["TimeLimit90"]"#)
+    );
+    assert_eq!(None, read(r#"["TIME_LIMIT_70"]"#));
+    assert_eq!(
+        r#"["TIME_LIMIT_90"]"#,
+        serde_json::to_string(&[MailType::TimeLimit90]).unwrap()
+    );
+}
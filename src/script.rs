@@ -0,0 +1,322 @@
+//! Rendering the batch scripts that run a podman container under Slurm.
+
+use anyhow::Context;
+
+use crate::{
+    catalog::{Image, podman_args_for_image, qualified_image_name, sbatch_args_for_image},
+    digest,
+    site::Site,
+    slurm,
+};
+
+/// A podman container as run by each task - the image, what it mounts, and its entrypoint.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct ContainerSpec {
+    /// Fully qualified podman image
+    pub image: String,
+    /// The digest `image` pointed to at submission, which each task runs
+    pub image_digest: Option<String>,
+    /// Podman args required by a known image
+    pub image_podman_args: Vec<String>,
+    /// Default sbatch resource requests of a known image
+    pub image_sbatch_args: Vec<String>,
+    /// Entrypoint of each container
+    pub command: String,
+    /// Host path of a user-defined shell script mounted inside each container
+    pub mounted_script: Option<String>,
+    /// Additional podman args from the user
+    #[serde(deserialize_with = "deserialize_words")]
+    pub podman_args: Vec<String>,
+    /// Expose the GPUs allocated to the job inside the container - requires the lines of
+    /// `gpu_setup` to run first
+    #[serde(default)]
+    pub gpu: bool,
+}
+
+impl ContainerSpec {
+    /// Runs `command` in `image`. If `command` has a shell script extension (.sh) and exists on
+    /// the host it is mounted inside the container at its canonical path.
+    pub fn new(image: Image, tag: Option<String>, command: String) -> anyhow::Result<Self> {
+        let (command, mounted_script) = {
+            let command_path = std::path::Path::new(&command);
+            if command_path.exists() && command_path.extension().is_some_and(|ext| ext == "sh") {
+                let mounted_command_path = std::fs::canonicalize(command_path).with_context(||
+                    format!("It looks like \"{command}\" is a shell script, but a canonical path cannot be determined for mounting in each container")
+                )?.to_str().with_context(||format!("\"{command}\" is not valid UTF-8"))?.to_string();
+                (mounted_command_path.clone(), Some(mounted_command_path))
+            } else {
+                (command, None)
+            }
+        };
+        Ok(ContainerSpec {
+            image_podman_args: podman_args_for_image(&image),
+            image_sbatch_args: sbatch_args_for_image(&image),
+            image: qualified_image_name(image, tag),
+            image_digest: None,
+            command,
+            mounted_script,
+            podman_args: Vec::new(),
+            gpu: false,
+        })
+    }
+
+    /// Pins the image to the digest its tag points to now.
+    pub fn pin(&mut self, site: &Site) -> anyhow::Result<()> {
+        self.image_digest = Some(digest::resolve(&self.image, &site.authfile)?);
+        Ok(())
+    }
+
+    /// The image as run by each task - pinned to its digest when known.
+    pub fn image_reference(&self) -> String {
+        match &self.image_digest {
+            Some(digest) => digest::pinned_name(&self.image, digest),
+            None => self.image.clone(),
+        }
+    }
+
+    /// Renders the bash lines that collect the podman args exposing the job's GPUs. Each GPU in
+    /// SLURM_JOB_GPUS is passed as a CDI device, and CUDA_VISIBLE_DEVICES is set to the indices
+    /// those devices have inside the container. Every GPU is passed outside of a Slurm allocation.
+    pub fn gpu_setup(&self) -> String {
+        if !self.gpu {
+            return "".to_string();
+        }
+        "GPU_ARGS=(--device=nvidia.com/gpu=all)
+if [[ -n ${SLURM_JOB_GPUS:-} ]]; then
+    GPU_ARGS=()
+    VISIBLE_DEVICES=()
+    for gpu in ${SLURM_JOB_GPUS//,/ }; do
+        GPU_ARGS+=(--device=nvidia.com/gpu=\"$gpu\")
+        VISIBLE_DEVICES+=(${#VISIBLE_DEVICES[@]})
+    done
+    GPU_ARGS+=(-e CUDA_VISIBLE_DEVICES=\"$(IFS=,; echo \"${VISIBLE_DEVICES[*]}\")\")
+fi
+"
+        .to_string()
+    }
+
+    /// Renders a "podman run" command line. `env_args` and `args`, the arguments to the
+    /// command, are bash words and must already be quoted.
+    pub fn podman_run(&self, site: &Site, env_args: &str, args: &str) -> String {
+        format!(
+            "podman run --rm \
+    {gpu_args} \
+    -v \"$HOME\":\"$HOME\" \
+    -e HPC_HOME=\"$HOME\" \
+    {env_args} \
+    {shared_mounts} \
+    {command_volume_arg} \
+    {additional_podman_args} \
+    --authfile {authfile} \
+    --entrypoint {command} \
+    {podman_args} \
+    {image} {args}",
+            gpu_args = if self.gpu {
+                "--security-opt=label=disable \"${GPU_ARGS[@]}\""
+            } else {
+                ""
+            },
+            shared_mounts = site
+                .shared_mounts
+                .iter()
+                .map(|path| format!("-v {}", shell_quote(&format!("{path}:{path}"))))
+                .collect::<Vec<_>>()
+                .join(" "),
+            authfile = shell_quote(&site.authfile),
+            command_volume_arg = match &self.mounted_script {
+                Some(path) => format!("-v {}", shell_quote(&format!("{path}:{path}"))),
+                None => "".to_string(),
+            },
+            additional_podman_args = quote_words(&self.image_podman_args),
+            command = shell_quote(&self.command),
+            podman_args = quote_words(&self.podman_args),
+            image = shell_quote(&self.image_reference()),
+        )
+    }
+}
+
+/// A batch script and the sbatch arguments it is submitted with.
+pub struct BatchScript {
+    pub sbatch_args: Vec<String>,
+    pub script: String,
+}
+
+impl BatchScript {
+    /// Prepends the settings every batch script shares to `body`.
+    pub fn new(site: &Site, sbatch_args: Vec<String>, body: &str) -> Self {
+        BatchScript {
+            sbatch_args,
+            script: format!(
+                "#!/bin/bash
+set -u
+{}
+{body}",
+                site.export_tmpdir()
+            ),
+        }
+    }
+}
+
+/// The sbatch command line, exactly as submitted, followed by the script, as printed by
+/// --dry-run.
+impl std::fmt::Display for BatchScript {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f,
+            "sbatch {}\n{}",
+            quote_words(&slurm::sbatch_argv(&self.sbatch_args)),
+            self.script
+        )
+    }
+}
+
+/// Quotes `s` so that bash reads it back as a single word with exactly the same bytes. Words made
+/// only of characters that bash never treats specially are left as is.
+pub fn shell_quote(s: &str) -> String {
+    if !s.is_empty()
+        && s.chars()
+            .all(|c| c.is_ascii_alphanumeric() || "_-+=@%:,./".contains(c))
+    {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace('\'', "'\\''"))
+    }
+}
+
+/// Quotes each of `words` and joins them with spaces.
+pub fn quote_words(words: &[String]) -> String {
+    words
+        .iter()
+        .map(|word| shell_quote(word))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Splits `words` into arguments like a POSIX shell, without expanding anything, and appends
+/// `more`. Options such as --sbatch-args take `words` and their repeated counterparts, such as
+/// --sbatch-arg, take `more`.
+pub fn split_words(words: Option<&str>, more: Vec<String>) -> anyhow::Result<Vec<String>> {
+    let mut split = match words {
+        Some(words) => shell_words::split(words)?,
+        None => Vec::new(),
+    };
+    split.extend(more);
+    Ok(split)
+}
+
+/// Reads arguments saved as a list, or as the single string of a manifest that predates lists.
+pub fn deserialize_words<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum Words {
+        Split(Vec<String>),
+        Unsplit(Option<String>),
+    }
+    match serde::Deserialize::deserialize(deserializer)? {
+        Words::Split(words) => Ok(words),
+        Words::Unsplit(words) => {
+            split_words(words.as_deref(), Vec::new()).map_err(serde::de::Error::custom)
+        }
+    }
+}
+
+/// Renders each row of arguments as one line of words for a bash array.
+pub fn command_args_script(rows: &[Vec<String>]) -> String {
+    rows.iter()
+        .map(|row| quote_words(row))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[test]
+fn quotes_shell_words() {
+    assert_eq!("M68123456", shell_quote("M68123456"));
+    assert_eq!("''", shell_quote(""));
+    assert_eq!("'a b'", shell_quote("a b"));
+    assert_eq!("'$(rm -rf ~)'", shell_quote("$(rm -rf ~)"));
+    assert_eq!("'it'\\''s'", shell_quote("it's"));
+}
+
+#[test]
+fn splits_shell_words() {
+    assert_eq!(
+        vec!["--comment=two words", "--export=A=1,B=x y", "--qos", "high"],
+        split_words(
+            Some("--comment=\"two words\" --export=A=1,B=\"x y\""),
+            vec!["--qos".to_string(), "high".to_string()]
+        )
+        .unwrap()
+    );
+    assert!(split_words(Some("--comment=\"unterminated"), Vec::new()).is_err());
+}
+
+#[test]
+fn renders_podman_run() {
+    let mut container = ContainerSpec::new(
+        Image::QualifiedName("docker.io/library/ubuntu:22.04".to_string()),
+        None,
+        "echo".to_string(),
+    )
+    .unwrap();
+    container.gpu = true;
+    container.image_digest = Some("sha256:abc".to_string());
+    let run = container.podman_run(&crate::site::built_in(), "", "'a b'");
+    assert!(run.starts_with("podman run --rm --security-opt=label=disable"));
+    assert!(run.contains("-v /mnt/home/shared/:/mnt/home/shared/ "));
+    assert!(run.contains("--entrypoint echo "));
+    assert!(run.ends_with(" docker.io/library/ubuntu@sha256:abc 'a b'"));
+}
+
+#[cfg(test)]
+mod shell_quote_properties {
+    use super::*;
+    use crate::argfile::parse_command_arg_file;
+    use proptest::prelude::*;
+
+    fn bash_words(script: &str) -> Vec<String> {
+        let output = std::process::Command::new("bash")
+            .arg("-c")
+            .arg(script)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout)
+            .unwrap()
+            .split_terminator('\0')
+            .map(str::to_string)
+            .collect()
+    }
+
+    proptest! {
+        #[test]
+        fn shell_quote_round_trips(s in "[^\0]*") {
+            prop_assert_eq!(
+                vec![s.clone()],
+                bash_words(&format!("printf '%s\\0' {}", shell_quote(&s)))
+            );
+        }
+
+        /// Lines are trimmed of surrounding whitespace, as --help says, and blank lines skipped -
+        /// everything in between reaches COMMAND byte for byte.
+        #[test]
+        fn trimmed_command_arg_lines_reach_command_unchanged(
+            lines in prop::collection::vec("[ \t]*[^\0\r\n]*[ \t]*", 1..8)
+        ) {
+            let trimmed = lines
+                .iter()
+                .map(|line| line.trim())
+                .filter(|line| !line.is_empty())
+                .collect::<Vec<_>>();
+            prop_assume!(!trimmed.is_empty());
+            let file = parse_command_arg_file(&lines.join("\n"), None, false).unwrap();
+            let script = format!(
+                "COMMAND_ARGS=(\n{}\n)\nprintf '%s\\0' \"${{COMMAND_ARGS[@]}}\"",
+                command_args_script(&file.rows)
+            );
+            prop_assert_eq!(trimmed, bash_words(&script));
+        }
+    }
+}
@@ -0,0 +1,126 @@
+//! Where things are on a cluster - scratch space, shared directories, registry credentials, and
+//! the files images need - so that the tools are not tied to IHN's paths.
+//!
+//! The built-in profile describes IHN's cluster. A profile file replaces the settings it sets and
+//! adds its `[paths]` to the built-in ones.
+
+use anyhow::{Context, anyhow};
+use std::collections::BTreeMap;
+
+/// The profile used when $IHN_HPC_SITE is not set, if it exists
+pub const SITE_PROFILE: &str = "/etc/ihn-hpc/site.toml";
+
+const BUILT_IN_SITE: &str = include_str!("site.toml");
+
+#[derive(serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Site {
+    /// Scratch directory on compute nodes, exported as TMPDIR - shell variables such as $USER are
+    /// expanded on the node
+    pub scratch: String,
+    /// Host directories mounted at the same location in every container
+    pub shared_mounts: Vec<String>,
+    /// Registry credentials used to pull images
+    pub authfile: String,
+    /// The site-wide image catalog
+    pub catalog: String,
+    /// Locations that catalog mounts refer to as ${name}
+    #[serde(default)]
+    pub paths: BTreeMap<String, String>,
+}
+
+impl Site {
+    /// Loads the profile named by $IHN_HPC_SITE, or else SITE_PROFILE if it exists, over the
+    /// built-in profile.
+    pub fn load() -> anyhow::Result<Site> {
+        let path = match std::env::var_os("IHN_HPC_SITE") {
+            Some(path) if !path.is_empty() => Some(std::path::PathBuf::from(path)),
+            _ => Some(std::path::PathBuf::from(SITE_PROFILE)).filter(|path| path.exists()),
+        };
+        let Some(path) = path else {
+            return Site::parse(BUILT_IN_SITE, None);
+        };
+        let contents = std::fs::read_to_string(&path)
+            .with_context(|| format!("Unable to read site profile {path:?}"))?;
+        Site::parse(BUILT_IN_SITE, Some(&contents))
+            .with_context(|| format!("Invalid site profile {path:?}"))
+    }
+
+    /// Parses `profile` over `built_in`.
+    fn parse(built_in: &str, profile: Option<&str>) -> anyhow::Result<Site> {
+        let mut site: toml::Table = toml::from_str(built_in).context("Invalid built-in profile")?;
+        if let Some(profile) = profile {
+            for (key, value) in toml::from_str::<toml::Table>(profile)? {
+                match (site.get_mut(&key), value) {
+                    (Some(toml::Value::Table(paths)), toml::Value::Table(more))
+                        if key == "paths" =>
+                    {
+                        paths.extend(more)
+                    }
+                    (_, value) => {
+                        site.insert(key, value);
+                    }
+                }
+            }
+        }
+        Ok(site.try_into()?)
+    }
+
+    /// Replaces each ${name} in `s` with the location `name` in [paths].
+    pub fn expand_paths(&self, s: &str) -> anyhow::Result<String> {
+        let mut expanded = String::new();
+        let mut rest = s;
+        while let Some(start) = rest.find("${") {
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| anyhow!("Unterminated \"${{\" in \"{s}\""))?;
+            let name = &rest[start + 2..start + end];
+            let path = self.paths.get(name).ok_or_else(|| {
+                anyhow!("\"{s}\" refers to ${{{name}}}, which is not in the site profile's [paths]")
+            })?;
+            expanded += &rest[..start];
+            expanded += path;
+            rest = &rest[start + end + 1..];
+        }
+        Ok(expanded + rest)
+    }
+
+    /// The bash line exporting the scratch directory as TMPDIR.
+    pub fn export_tmpdir(&self) -> String {
+        format!("export TMPDIR=\"{}\"", self.scratch)
+    }
+}
+
+#[cfg(test)]
+pub fn built_in() -> Site {
+    Site::parse(BUILT_IN_SITE, None).unwrap()
+}
+
+#[test]
+fn profiles_replace_built_in_settings() {
+    let site = Site::parse(
+        BUILT_IN_SITE,
+        Some(
+            r#"
+scratch = "/scratch/$USER"
+shared_mounts = []
+[paths]
+fs_license = "/opt/licenses/freesurfer.txt"
+"#,
+        ),
+    )
+    .unwrap();
+    assert_eq!("/scratch/$USER", site.scratch);
+    assert!(site.shared_mounts.is_empty());
+    assert_eq!("/mnt/apps/etc/auth.json", site.authfile);
+    assert_eq!(
+        "/opt/licenses/freesurfer.txt:/license:ro",
+        site.expand_paths("${fs_license}:/license:ro").unwrap()
+    );
+    assert_eq!(
+        "/opt/matlab/runtime/R2019b/v97/",
+        site.expand_paths("${matlab_runtime}").unwrap()
+    );
+    assert!(site.expand_paths("${nope}").is_err());
+    assert!(Site::parse(BUILT_IN_SITE, Some("scrach = \"/tmp\"")).is_err());
+}
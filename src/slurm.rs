@@ -0,0 +1,514 @@
+use anyhow::{Context, anyhow};
+use std::io::Write;
+
+use crate::gpu::GpuRequest;
+
+/// Slurm's MaxArraySize when it is not configured
+pub const DEFAULT_MAX_ARRAY_SIZE: usize = 1001;
+
+/// Reads MaxArraySize from "scontrol show config".
+pub fn max_array_size() -> anyhow::Result<usize> {
+    let output = std::process::Command::new("scontrol")
+        .args(["show", "config"])
+        .output()
+        .context("Unable to invoke scontrol")?;
+    if !output.status.success() {
+        return Err(anyhow!("scontrol show config failed"));
+    }
+    parse_max_array_size(&String::from_utf8_lossy(&output.stdout))
+        .context("Unable to determine MaxArraySize")
+}
+
+fn parse_max_array_size(config: &str) -> Option<usize> {
+    config.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        if key.trim() == "MaxArraySize" {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+/// The arguments sbatch is invoked with to submit a batch script with `args`.
+pub fn sbatch_argv(args: &[String]) -> Vec<String> {
+    std::iter::once("--parsable".to_string())
+        .chain(args.iter().cloned())
+        .collect()
+}
+
+/// Submits a batch script with "sbatch --parsable". sbatch's stderr is passed through and its
+/// stdout captured.
+pub fn sbatch(args: &[String], script: &str) -> anyhow::Result<std::process::Output> {
+    let mut sbatch_child = std::process::Command::new("sbatch")
+        .args(sbatch_argv(args))
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .context("Unable to invoke sbatch")?;
+    if let Some(mut stdin) = sbatch_child.stdin.take() {
+        writeln!(stdin, "{script}")?;
+    } else {
+        return Err(anyhow!("Unable to take stdin of sbatch"));
+    }
+    Ok(sbatch_child.wait_with_output()?)
+}
+
+/// Parses the "jobid[;cluster]" printed by "sbatch --parsable".
+pub fn parse_job_id(stdout: &[u8]) -> anyhow::Result<String> {
+    let stdout = String::from_utf8_lossy(stdout);
+    let job_id = stdout.trim().split(';').next().unwrap_or_default();
+    if job_id.is_empty() || !job_id.chars().all(|c| c.is_ascii_digit()) {
+        return Err(anyhow!("Unexpected sbatch output \"{}\"", stdout.trim()));
+    }
+    Ok(job_id.to_string())
+}
+
+/// Releases a job submitted with --hold.
+pub fn release(job_id: &str) -> anyhow::Result<()> {
+    run_quietly("scontrol", &["release", job_id])
+}
+
+/// Cancels a job.
+pub fn cancel(job_id: &str) -> anyhow::Result<()> {
+    run_quietly("scancel", &[job_id])
+}
+
+fn run_quietly(program: &str, args: &[&str]) -> anyhow::Result<()> {
+    let output = std::process::Command::new(program)
+        .args(args)
+        .output()
+        .with_context(|| format!("Unable to invoke {program}"))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{program} {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(())
+}
+
+pub struct ArrayTask {
+    pub job_id: String,
+    pub index: usize,
+    pub state: String,
+    pub exit_code: String,
+    pub elapsed: String,
+    pub node_list: String,
+    /// The largest resident set size of any step, in KiB
+    pub max_rss_kib: Option<u64>,
+}
+
+/// Reads every task of the given job arrays from sacct.
+pub fn array_tasks(job_ids: &[String]) -> anyhow::Result<Vec<ArrayTask>> {
+    let output = std::process::Command::new("sacct")
+        .args(["--noheader", "--parsable2"])
+        .arg("--format=JobID,State,ExitCode,Elapsed,NodeList,MaxRSS")
+        .arg(format!("--jobs={}", job_ids.join(",")))
+        .output()
+        .context("Unable to invoke sacct")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "sacct failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    parse_array_tasks(&String::from_utf8_lossy(&output.stdout))
+}
+
+/// Parses "JobID|State|ExitCode|Elapsed|NodeList|MaxRSS" lines of sacct. Each task is listed
+/// before its steps, e.g. "12_3" then "12_3.batch", and pending tasks are listed together, e.g.
+/// "12_[3-5,7%16]". A job that is not an array, e.g. "13" then "13.batch", is its only task 0.
+fn parse_array_tasks(sacct: &str) -> anyhow::Result<Vec<ArrayTask>> {
+    let arrays = sacct
+        .lines()
+        .filter_map(|line| Some(line.split('|').next()?.split_once('_')?.0))
+        .collect::<std::collections::BTreeSet<_>>();
+    let mut tasks: Vec<ArrayTask> = Vec::new();
+    for line in sacct.lines().filter(|line| !line.trim().is_empty()) {
+        let unexpected = || anyhow!("Unexpected sacct output \"{line}\"");
+        let fields = line.split('|').collect::<Vec<_>>();
+        let [job, state, exit_code, elapsed, node_list, max_rss] = fields[..] else {
+            return Err(unexpected());
+        };
+        let single_job_indices;
+        let (job_id, indices) = match job.split_once('_') {
+            Some(split) => split,
+            None => {
+                let (job_id, step) = job
+                    .split_once('.')
+                    .map_or((job, None), |(job_id, step)| (job_id, Some(step)));
+                if arrays.contains(job_id) {
+                    continue;
+                }
+                single_job_indices = match step {
+                    Some(step) => format!("0.{step}"),
+                    None => "0".to_string(),
+                };
+                (job_id, single_job_indices.as_str())
+            }
+        };
+        if let Some((index, _step)) = indices.split_once('.') {
+            let index = index.parse::<usize>().map_err(|_| unexpected())?;
+            if let (Some(task), Some(rss)) = (
+                tasks
+                    .iter_mut()
+                    .rev()
+                    .find(|task| task.job_id == job_id && task.index == index),
+                parse_kib(max_rss),
+            ) {
+                task.max_rss_kib = Some(task.max_rss_kib.map_or(rss, |max| max.max(rss)));
+            }
+            continue;
+        }
+        let indices = match indices.strip_prefix('[') {
+            Some(ranges) => {
+                let ranges = ranges.trim_end_matches(']');
+                let ranges = ranges.split('%').next().unwrap_or_default();
+                let mut indices = Vec::new();
+                for range in ranges.split(',') {
+                    let (first, last) = range.split_once('-').unwrap_or((range, range));
+                    let first = first.parse::<usize>().map_err(|_| unexpected())?;
+                    let last = last.parse::<usize>().map_err(|_| unexpected())?;
+                    indices.extend(first..=last);
+                }
+                indices
+            }
+            None => vec![indices.parse().map_err(|_| unexpected())?],
+        };
+        // e.g. "CANCELLED by 1000"
+        let state = state.split_whitespace().next().unwrap_or_default();
+        tasks.extend(indices.into_iter().map(|index| ArrayTask {
+            job_id: job_id.to_string(),
+            index,
+            state: state.to_string(),
+            exit_code: exit_code.to_string(),
+            elapsed: elapsed.to_string(),
+            node_list: node_list.to_string(),
+            max_rss_kib: None,
+        }));
+    }
+    Ok(tasks)
+}
+
+/// Parses a sacct memory size, e.g. "1.50G", into KiB. Sizes without a unit are in bytes.
+fn parse_kib(size: &str) -> Option<u64> {
+    let size = size.trim();
+    let (number, multiplier) = match size.chars().last()? {
+        'K' => (&size[..size.len() - 1], 1.0),
+        'M' => (&size[..size.len() - 1], 1024.0),
+        'G' => (&size[..size.len() - 1], 1024.0 * 1024.0),
+        'T' => (&size[..size.len() - 1], 1024.0 * 1024.0 * 1024.0),
+        _ => (size, 1.0 / 1024.0),
+    };
+    number
+        .parse::<f64>()
+        .ok()
+        .map(|number| (number * multiplier).round() as u64)
+}
+
+/// Expands a Slurm hostlist, e.g. "node[01-03],gpu1", with "scontrol show hostnames".
+pub fn expand_nodelist(nodelist: &str) -> anyhow::Result<Vec<String>> {
+    let output = std::process::Command::new("scontrol")
+        .args(["show", "hostnames", nodelist])
+        .output()
+        .context("Unable to invoke scontrol")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Unable to expand nodelist \"{nodelist}\": {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .split_whitespace()
+        .map(str::to_string)
+        .collect())
+}
+
+/// Lists the nodes of a partition that can run jobs. Down, drained, and otherwise unavailable
+/// nodes are left out with a warning.
+pub fn partition_nodes(partition: &str) -> anyhow::Result<Vec<String>> {
+    let output = std::process::Command::new("sinfo")
+        .args(["--noheader", "--Node", "--format=%N %t"])
+        .arg(format!("--partition={partition}"))
+        .output()
+        .context("Unable to invoke sinfo")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Unable to list the nodes of partition {partition}: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let (nodes, unavailable) = parse_node_states(&String::from_utf8_lossy(&output.stdout));
+    if !unavailable.is_empty() {
+        eprintln!("WARN: skipping unavailable nodes {}", unavailable.join(","));
+    }
+    if nodes.is_empty() {
+        return Err(anyhow!("Partition {partition} has no available nodes"));
+    }
+    Ok(nodes)
+}
+
+/// Splits "NODE STATE" lines of sinfo into available and unavailable nodes.
+fn parse_node_states(sinfo: &str) -> (Vec<String>, Vec<String>) {
+    let mut available = Vec::new();
+    let mut unavailable = Vec::new();
+    for line in sinfo.lines() {
+        let Some((node, state)) = line.split_once(' ') else {
+            continue;
+        };
+        // Flags such as "*" (not responding) or "~" (powered off) follow the state
+        let usable = matches!(state.trim(), "idle" | "mix" | "alloc" | "comp");
+        let nodes = if usable {
+            &mut available
+        } else {
+            &mut unavailable
+        };
+        if !nodes.iter().any(|n| n == node) {
+            nodes.push(node.to_string());
+        }
+    }
+    (available, unavailable)
+}
+
+/// Lists the GPUs of each node configuration from the GRES advertised by "sinfo --format=%G".
+pub fn gpu_gres() -> anyhow::Result<Vec<GpuRequest>> {
+    let output = std::process::Command::new("sinfo")
+        .args(["--noheader", "--format=%G"])
+        .output()
+        .context("Unable to invoke sinfo")?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Unable to list the cluster's GRES: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(parse_gpu_gres(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Parses GRES such as "gpu:a100:4(S:0-1),shard:8", one node configuration per line.
+fn parse_gpu_gres(sinfo: &str) -> Vec<GpuRequest> {
+    let mut gpus = Vec::new();
+    for line in sinfo.lines() {
+        // Socket affinity, e.g. "(S:0,1)", may itself contain commas
+        let mut gres = String::new();
+        let mut depth = 0;
+        for c in line.trim().chars() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                c if depth == 0 => gres.push(c),
+                _ => {}
+            }
+        }
+        for entry in gres.split(',') {
+            let mut parts = entry.split(':');
+            if parts.next() != Some("gpu") {
+                continue;
+            }
+            let (model, count) = match (parts.next(), parts.next()) {
+                (Some(count), None) => (None, count),
+                (Some(model), Some(count)) => (Some(model.to_string()), count),
+                _ => continue,
+            };
+            if let Ok(count) = count.parse() {
+                gpus.push(GpuRequest { model, count });
+            }
+        }
+    }
+    gpus
+}
+
+/// What a script printed on a node, stdout and stderr combined.
+pub struct NodeOutput {
+    pub node: String,
+    pub lines: Vec<String>,
+    /// None when the script did not run to completion
+    pub exit_code: Option<i32>,
+}
+
+impl NodeOutput {
+    pub fn success(&self) -> bool {
+        self.exit_code == Some(0)
+    }
+}
+
+/// Runs a bash script once on each node with srun, waiting for every node to finish.
+pub fn run_on_nodes(
+    nodes: &[String],
+    partition: Option<&str>,
+    script: &str,
+) -> anyhow::Result<Vec<NodeOutput>> {
+    let mut srun = std::process::Command::new("srun");
+    srun.arg(format!("--nodelist={}", nodes.join(",")))
+        .arg(format!("--nodes={}", nodes.len()))
+        .args(["--ntasks-per-node=1", "--label"]);
+    if let Some(partition) = partition {
+        srun.arg(format!("--partition={partition}"));
+    }
+    let output = srun
+        .args(["bash", "-c"])
+        .arg(format!(
+            "printf '__node %s\\n' \"$SLURMD_NODENAME\"
+{{
+{script}
+}} 2>&1
+printf '__exit %s\\n' \"$?\""
+        ))
+        .stderr(std::process::Stdio::inherit())
+        .output()
+        .context("Unable to invoke srun")?;
+    let mut outputs = parse_node_outputs(&String::from_utf8_lossy(&output.stdout));
+    for node in nodes {
+        if !outputs.iter().any(|output| output.node == *node) {
+            outputs.push(NodeOutput {
+                node: node.clone(),
+                lines: Vec::new(),
+                exit_code: None,
+            });
+        }
+    }
+    outputs.sort_by(|a, b| a.node.cmp(&b.node));
+    Ok(outputs)
+}
+
+/// Parses the "TASK: LINE" output of "srun --label" for scripts run by `run_on_nodes`.
+fn parse_node_outputs(stdout: &str) -> Vec<NodeOutput> {
+    let mut tasks = std::collections::BTreeMap::<&str, NodeOutput>::new();
+    for line in stdout.lines() {
+        let Some((task, line)) = line.split_once(": ") else {
+            continue;
+        };
+        let output = tasks.entry(task.trim()).or_insert_with(|| NodeOutput {
+            node: String::new(),
+            lines: Vec::new(),
+            exit_code: None,
+        });
+        if let Some(node) = line.strip_prefix("__node ") {
+            output.node = node.to_string();
+        } else if let Some(code) = line.strip_prefix("__exit ") {
+            output.exit_code = code.trim().parse().ok();
+        } else {
+            output.lines.push(line.to_string());
+        }
+    }
+    tasks
+        .into_values()
+        .filter(|output| !output.node.is_empty())
+        .collect()
+}
+
+#[test]
+fn parses_max_array_size() {
+    assert_eq!(
+        Some(40001),
+        parse_max_array_size(
+            "Configuration data as of 2025-01-01T00:00:00
+MailProg                = /bin/mail
+MaxArraySize            = 40001
+MaxDBDMsgs              = 20008
+"
+        )
+    );
+    assert_eq!(None, parse_max_array_size("MaxJobCount = 10000\n"));
+}
+
+#[test]
+fn parses_job_id() {
+    assert_eq!("1234", parse_job_id(b"1234\n").unwrap());
+    assert_eq!("1234", parse_job_id(b"1234;cluster\n").unwrap());
+    assert!(parse_job_id(b"sbatch: error\n").is_err());
+}
+
+#[test]
+fn parses_array_tasks() {
+    let tasks = parse_array_tasks(
+        "12_[3-5,7%16]|PENDING|0:0|00:00:00|None assigned|
+12_0|CANCELLED by 1000|0:0|00:01:00|node1|
+12_0.batch|CANCELLED|0:15|00:01:00|node1|2048K
+12_0.extern|COMPLETED|0:0|00:01:00|node1|1.50M
+12_0.0|CANCELLED|0:15|00:01:00|node1|10.25G
+12|COMPLETED|0:0|00:01:00|node1|
+",
+    )
+    .unwrap();
+    assert_eq!(
+        vec![3, 4, 5, 7, 0],
+        tasks.iter().map(|task| task.index).collect::<Vec<_>>()
+    );
+    assert!(
+        tasks[..4]
+            .iter()
+            .all(|task| task.job_id == "12" && task.state == "PENDING")
+    );
+    assert_eq!("CANCELLED", tasks[4].state);
+    assert_eq!("node1", tasks[4].node_list);
+    assert_eq!(Some(10747904), tasks[4].max_rss_kib);
+    assert_eq!(None, tasks[0].max_rss_kib);
+
+    let tasks = parse_array_tasks(
+        "13|OUT_OF_MEMORY|0:125|00:02:00|node2|
+13.batch|OUT_OF_MEMORY|0:125|00:02:00|node2|4G
+",
+    )
+    .unwrap();
+    assert_eq!(1, tasks.len());
+    assert_eq!(("13", 0), (tasks[0].job_id.as_str(), tasks[0].index));
+    assert_eq!("OUT_OF_MEMORY", tasks[0].state);
+    assert_eq!(Some(4194304), tasks[0].max_rss_kib);
+}
+
+#[test]
+fn parses_node_states() {
+    assert_eq!(
+        (
+            vec!["node01".to_string(), "node02".to_string()],
+            vec!["node03".to_string(), "node04".to_string()]
+        ),
+        parse_node_states(
+            "node01 idle
+node02 mix
+node02 mix
+node03 drain
+node04 down*
+"
+        )
+    );
+}
+
+#[test]
+fn parses_gpu_gres() {
+    assert_eq!(
+        vec![
+            GpuRequest {
+                model: Some("a100".to_string()),
+                count: 4
+            },
+            GpuRequest {
+                model: None,
+                count: 2
+            },
+        ],
+        parse_gpu_gres("gpu:a100:4(S:0,1),shard:8\n(null)\ngpu:2\n")
+    );
+}
+
+#[test]
+fn parses_node_outputs() {
+    let outputs = parse_node_outputs(
+        " 0: __node node01
+ 1: __node node02
+ 0: sha256:abc
+ 1: Error: unable to pull
+ 1: __exit 125
+ 0: __exit 0
+",
+    );
+    assert_eq!(2, outputs.len());
+    assert_eq!("node01", outputs[0].node);
+    assert_eq!(vec!["sha256:abc"], outputs[0].lines);
+    assert!(outputs[0].success());
+    assert_eq!("node02", outputs[1].node);
+    assert_eq!(Some(125), outputs[1].exit_code);
+}
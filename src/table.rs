@@ -0,0 +1,40 @@
+//! Plain-text tables printed by the subcommands.
+
+/// Prints left-aligned columns separated by two spaces.
+pub fn print_table(table: &[Vec<String>]) {
+    let mut widths = Vec::<usize>::new();
+    for row in table {
+        widths.resize(widths.len().max(row.len()), 0);
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+    for row in table {
+        let line = row
+            .iter()
+            .zip(&widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect::<Vec<_>>()
+            .join("  ");
+        println!("{}", line.trim_end());
+    }
+}
+
+/// Formats a size in KiB with a binary unit, e.g. "1.5G".
+pub fn format_kib(kib: u64) -> String {
+    let mut size = kib as f64;
+    for unit in ["K", "M", "G"] {
+        if size < 1024.0 {
+            return format!("{size:.1}{unit}");
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.1}T")
+}
+
+#[test]
+fn formats_kib() {
+    assert_eq!("512.0K", format_kib(512));
+    assert_eq!("1.5M", format_kib(1536));
+    assert_eq!("10.2G", format_kib(10747904));
+}
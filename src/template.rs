@@ -0,0 +1,355 @@
+//! Command templates in the style of GNU parallel, e.g. "recon-all -s {1} -i /data/{1}/{2}.nii",
+//! filled in with the arguments of each task.
+//!
+//! Placeholders:
+//! - `{}` every argument of the task - as separate arguments when it is a word of its own
+//! - `{N}` the Nth argument, from 1
+//! - `{NAME}` the argument of the column named NAME by --header
+//! - `{#}` the number of the task's line among the arguments, from 1 - the same when retried
+//! - `{%}` the task's index in its job array, SLURM_ARRAY_TASK_ID
+//!
+//! Arguments may be modified: `{1.}` without its extension, `{1/}` its base name, `{1//}` its
+//! directory, and `{1/.}` its base name without its extension, likewise `{.}`, `{NAME/}`, etc.
+
+use anyhow::anyhow;
+
+use crate::argfile::CommandArgFile;
+
+/// The arguments of COMMAND in a template, parsed.
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Template {
+    pub words: Vec<Vec<Part>>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Part {
+    Text(String),
+    /// The argument in a column, counted from 0, or every argument when none
+    Argument {
+        column: Option<usize>,
+        modifier: Modifier,
+    },
+    /// {#}
+    Number,
+    /// {%}
+    ArrayIndex,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Modifier {
+    None,
+    /// {.}
+    NoExtension,
+    /// {/}
+    Basename,
+    /// {//}
+    Dirname,
+    /// {/.}
+    BasenameNoExtension,
+}
+
+/// What the braces of a placeholder select, before columns are resolved.
+enum Selector<'a> {
+    All,
+    Column(usize),
+    Name(&'a str),
+    Number,
+    ArrayIndex,
+}
+
+/// Splits COMMAND into the command and, if it has any placeholders, a template of its arguments
+/// filled in from the rows of `command_arg_file`.
+pub fn parse_command(
+    command: &str,
+    command_arg_file: &CommandArgFile,
+) -> anyhow::Result<(String, Option<Template>)> {
+    let (command, words) = split_command(command)?;
+    let template = match words {
+        Some(words) => Some(Template::parse(
+            &words,
+            command_arg_file.header.as_deref(),
+            command_arg_file.rows.first().map_or(0, Vec::len),
+        )?),
+        None => None,
+    };
+    Ok((command, template))
+}
+
+/// Splits COMMAND into the command and a template of its arguments if it has any placeholders,
+/// e.g. "recon-all -s {1}" into "recon-all" and ["-s", "{1}"]. Commands without placeholders are
+/// left as is, their tasks' arguments appended.
+fn split_command(command: &str) -> anyhow::Result<(String, Option<Vec<String>>)> {
+    if !has_placeholder(command) {
+        return Ok((command.to_string(), None));
+    }
+    let mut words = shell_words::split(command)
+        .map_err(|what| anyhow!("Unable to split COMMAND into words: {what}"))?
+        .into_iter();
+    let command = words.next().unwrap_or_default();
+    if has_placeholder(&command) {
+        return Err(anyhow!(
+            "The first word of COMMAND, \"{command}\", is the command itself and cannot contain \
+             placeholders"
+        ));
+    }
+    Ok((command, Some(words.collect())))
+}
+
+impl Template {
+    /// Parses the words of a template for tasks of `arg_count` arguments, named by `header`.
+    pub fn parse(
+        words: &[String],
+        header: Option<&[String]>,
+        arg_count: usize,
+    ) -> anyhow::Result<Template> {
+        let words = words
+            .iter()
+            .map(|word| parse_word(word, header, arg_count))
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Template { words })
+    }
+
+    /// The arguments of COMMAND for the task of `row`, the task numbered `number` of its
+    /// submission and `array_index` of its job array. Each task has as many as every other.
+    pub fn expand(&self, row: &[String], number: usize, array_index: usize) -> Vec<String> {
+        let mut args = Vec::new();
+        for word in &self.words {
+            // A word of its own, {} stands for every argument as separate arguments
+            if let [
+                Part::Argument {
+                    column: None,
+                    modifier,
+                },
+            ] = word.as_slice()
+            {
+                args.extend(row.iter().map(|arg| modify(arg, *modifier)));
+                continue;
+            }
+            let mut arg = String::new();
+            for part in word {
+                match part {
+                    Part::Text(text) => arg += text,
+                    Part::Argument {
+                        column: Some(column),
+                        modifier,
+                    } => arg += &modify(&row[*column], *modifier),
+                    Part::Argument {
+                        column: None,
+                        modifier,
+                    } => {
+                        arg += &row
+                            .iter()
+                            .map(|arg| modify(arg, *modifier))
+                            .collect::<Vec<_>>()
+                            .join(" ")
+                    }
+                    Part::Number => arg += &number.to_string(),
+                    Part::ArrayIndex => arg += &array_index.to_string(),
+                }
+            }
+            args.push(arg);
+        }
+        args
+    }
+}
+
+fn has_placeholder(s: &str) -> bool {
+    placeholders(s).any(|(_, inner)| parse_placeholder(inner).is_some())
+}
+
+/// The byte offset and contents of each pair of braces in `word`.
+fn placeholders(word: &str) -> impl Iterator<Item = (usize, &str)> {
+    let mut rest = 0;
+    std::iter::from_fn(move || {
+        let start = rest + word[rest..].find('{')?;
+        let end = start + word[start..].find('}')?;
+        rest = end + 1;
+        Some((start, &word[start + 1..end]))
+    })
+}
+
+/// Parses the contents of braces, None when they are not a placeholder, e.g. "print $1" of awk.
+fn parse_placeholder(inner: &str) -> Option<(Selector<'_>, Modifier)> {
+    let (selector, modifier) = [
+        ("/.", Modifier::BasenameNoExtension),
+        ("//", Modifier::Dirname),
+        ("/", Modifier::Basename),
+        (".", Modifier::NoExtension),
+    ]
+    .into_iter()
+    .find_map(|(suffix, modifier)| Some((inner.strip_suffix(suffix)?, modifier)))
+    .unwrap_or((inner, Modifier::None));
+    let selector = match selector {
+        "" => Selector::All,
+        "#" if modifier == Modifier::None => Selector::Number,
+        "%" if modifier == Modifier::None => Selector::ArrayIndex,
+        digits if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) => {
+            Selector::Column(digits.parse().ok()?)
+        }
+        name if name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            Selector::Name(name)
+        }
+        _ => return None,
+    };
+    Some((selector, modifier))
+}
+
+fn parse_word(
+    word: &str,
+    header: Option<&[String]>,
+    arg_count: usize,
+) -> anyhow::Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut text_start = 0;
+    for (start, inner) in placeholders(word) {
+        let Some((selector, modifier)) = parse_placeholder(inner) else {
+            continue;
+        };
+        let part = match selector {
+            Selector::All => Part::Argument {
+                column: None,
+                modifier,
+            },
+            Selector::Column(number) => {
+                if number == 0 || number > arg_count {
+                    return Err(anyhow!(
+                        "{{{inner}}} in COMMAND refers to argument {number}, but each task has \
+                         {arg_count}"
+                    ));
+                }
+                Part::Argument {
+                    column: Some(number - 1),
+                    modifier,
+                }
+            }
+            Selector::Name(name) => {
+                let header = header.ok_or_else(|| {
+                    anyhow!("{{{inner}}} in COMMAND names a column, but only --header names them")
+                })?;
+                let column = header
+                    .iter()
+                    .position(|column| column == name)
+                    .ok_or_else(|| {
+                        anyhow!(
+                            "{{{inner}}} in COMMAND names no column - the header names {}",
+                            header.join(", ")
+                        )
+                    })?;
+                Part::Argument {
+                    column: Some(column),
+                    modifier,
+                }
+            }
+            Selector::Number => Part::Number,
+            Selector::ArrayIndex => Part::ArrayIndex,
+        };
+        if text_start < start {
+            parts.push(Part::Text(word[text_start..start].to_string()));
+        }
+        parts.push(part);
+        text_start = start + inner.len() + 2;
+    }
+    if text_start < word.len() || parts.is_empty() {
+        parts.push(Part::Text(word[text_start..].to_string()));
+    }
+    Ok(parts)
+}
+
+/// Applies `modifier` to an argument like GNU parallel would, treating it as a path.
+fn modify(arg: &str, modifier: Modifier) -> String {
+    let (dir, name) = match arg.rsplit_once('/') {
+        Some((dir, name)) => (Some(dir), name),
+        None => (None, arg),
+    };
+    let stem = match name.rfind('.') {
+        Some(dot) if dot > 0 => &name[..dot],
+        _ => name,
+    };
+    match modifier {
+        Modifier::None => arg.to_string(),
+        Modifier::NoExtension => match dir {
+            Some(dir) => format!("{dir}/{stem}"),
+            None => stem.to_string(),
+        },
+        Modifier::Basename => name.to_string(),
+        Modifier::Dirname => match dir {
+            Some("") => "/".to_string(),
+            Some(dir) => dir.to_string(),
+            None => ".".to_string(),
+        },
+        Modifier::BasenameNoExtension => stem.to_string(),
+    }
+}
+
+#[test]
+fn fills_in_placeholders() {
+    let (command, words) =
+        split_command("recon-all -s {1} -i '/data/{1}/{2}.nii' -sd {out} {} '{print $1}' {#}/{%}")
+            .unwrap();
+    assert_eq!("recon-all", command);
+    let header = ["subject", "scan", "out"].map(str::to_string);
+    let template = Template::parse(&words.unwrap(), Some(&header), 3).unwrap();
+    let row = ["sub-01", "t1 w", "/out"].map(str::to_string);
+    assert_eq!(
+        vec![
+            "-s",
+            "sub-01",
+            "-i",
+            "/data/sub-01/t1 w.nii",
+            "-sd",
+            "/out",
+            "sub-01",
+            "t1 w",
+            "/out",
+            "{print $1}",
+            "5/2",
+        ],
+        template.expand(&row, 5, 2)
+    );
+
+    assert_eq!(("echo".to_string(), None), split_command("echo").unwrap());
+    assert!(split_command("{1} x").is_err());
+    for (words, header) in [
+        ("{0}", None),
+        ("{4}", None),
+        ("{x}", None),
+        ("{x}", Some(&header)),
+    ] {
+        assert!(Template::parse(&[words.to_string()], header.map(|h| &h[..]), 3).is_err());
+    }
+}
+
+#[test]
+fn modifies_arguments_as_paths() {
+    let cases = [
+        (
+            "/data/sub-01/t1.nii.gz",
+            "/data/sub-01/t1.nii",
+            "t1.nii.gz",
+            "/data/sub-01",
+            "t1.nii",
+        ),
+        ("t1.nii", "t1", "t1.nii", ".", "t1"),
+        ("/t1", "/t1", "t1", "/", "t1"),
+        (
+            "dir.d/.hidden",
+            "dir.d/.hidden",
+            ".hidden",
+            "dir.d",
+            ".hidden",
+        ),
+    ];
+    for (arg, no_extension, basename, dirname, basename_no_extension) in cases {
+        assert_eq!(no_extension, modify(arg, Modifier::NoExtension));
+        assert_eq!(basename, modify(arg, Modifier::Basename));
+        assert_eq!(dirname, modify(arg, Modifier::Dirname));
+        assert_eq!(
+            basename_no_extension,
+            modify(arg, Modifier::BasenameNoExtension)
+        );
+    }
+}
@@ -0,0 +1,293 @@
+//! A stand-in Slurm cluster for the integration tests. Fake sbatch, srun, squeue, sacct,
+//! scontrol, scancel, sinfo, skopeo, and podman executables on PATH record each call, and
+//! submitted jobs run right away - or once released - one task after another.
+
+// Each test binary uses only some of the helpers
+#![allow(dead_code)]
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus},
+};
+
+/// Records the argv of a call, NUL-separated, in a directory of its own under calls/, named so
+/// that calls sort in the order they were made.
+const RECORD_CALL: &str = r#"#!/bin/bash
+call=$(mktemp -d "$FAKE_CLUSTER/calls/$(date +%s%N)-TOOL-XXXXXX")
+printf '%s\0' "$@" > "$call/argv"
+"#;
+
+const SBATCH: &str = r#"cat > "$call/stdin"
+if [[ -e $FAKE_CLUSTER/sbatch_fails ]]; then
+    echo "sbatch: error: Batch job submission failed: Invalid account or account/partition combination specified" >&2
+    exit 1
+fi
+job_id=$(( $(cat "$FAKE_CLUSTER/last_job_id" 2>/dev/null || echo 1000) + 1 ))
+echo "$job_id" > "$FAKE_CLUSTER/last_job_id"
+job=$FAKE_CLUSTER/jobs/$job_id
+mkdir -p "$job"
+cp "$call/stdin" "$job/script"
+cp "$call/argv" "$job/argv"
+if ! grep -qzx -- --hold "$job/argv"; then
+    fake-run-job "$job_id"
+fi
+echo "$job_id"
+"#;
+
+/// Runs each task of a job like slurmd would, appending "TASK EXIT_CODE" to its exit_codes.
+const RUN_JOB: &str = r#"#!/bin/bash
+job_id=$1
+job=$FAKE_CLUSTER/jobs/$job_id
+array=
+output=$job/slurm-%A_%a.out
+error=
+gpus=
+while IFS= read -r -d '' arg; do
+    case $arg in
+        --array=*) array=${arg#--array=} ;;
+        --output=*) output=${arg#--output=} ;;
+        --error=*) error=${arg#--error=} ;;
+        --gres=gpu:*) gpus=${arg##*:} ;;
+    esac
+done < "$job/argv"
+error=${error:-$output}
+range=${array%%\%*}
+range=${range:-0-0}
+task_env=()
+[[ -n $gpus ]] && task_env+=(SLURM_JOB_GPUS="$(seq -s, 0 $((gpus - 1)))")
+for task in $(seq "${range%-*}" "${range#*-}"); do
+    out=${output//%A/$job_id}
+    err=${error//%A/$job_id}
+    if [[ -n $array ]]; then
+        env "${task_env[@]}" SLURM_JOB_ID="$job_id" SLURM_ARRAY_JOB_ID="$job_id" \
+            SLURM_ARRAY_TASK_ID="$task" bash "$job/script" > "${out//%a/$task}" 2> "${err//%a/$task}"
+    else
+        env "${task_env[@]}" SLURM_JOB_ID="$job_id" bash "$job/script" > "${out//%a/0}" 2> "${err//%a/0}"
+    fi
+    echo "$task $?" >> "$job/exit_codes"
+done
+"#;
+
+const SCONTROL: &str = r#"case "$1" in
+    show)
+        case "$2" in
+            config) echo "MaxArraySize            = 1001" ;;
+            hostnames) tr ',' '\n' <<< "$3" ;;
+            *) exit 1 ;;
+        esac
+        ;;
+    release)
+        if [[ -e $FAKE_CLUSTER/release_fails ]]; then
+            echo "scontrol: error: Access/permission denied for job $2" >&2
+            exit 1
+        fi
+        if [[ ! -d $FAKE_CLUSTER/jobs/$2 ]]; then
+            echo "scontrol: error: Invalid job id specified" >&2
+            exit 1
+        fi
+        fake-run-job "$2"
+        ;;
+    *) exit 1 ;;
+esac
+"#;
+
+/// Reports each task that has run, and every other task of the requested jobs as pending. Jobs
+/// that are not arrays are listed by their job ID alone.
+const SACCT: &str = r#"for arg; do
+    [[ $arg == --jobs=* ]] && job_ids=${arg#--jobs=}
+done
+for job_id in ${job_ids//,/ }; do
+    exit_codes=$FAKE_CLUSTER/jobs/$job_id/exit_codes
+    [[ -f $exit_codes ]] || continue
+    while read -r task code; do
+        state=COMPLETED
+        [[ $code != 0 ]] && state=FAILED
+        id=${job_id}_$task
+        grep -qz -- '^--array=' "$FAKE_CLUSTER/jobs/$job_id/argv" || id=$job_id
+        echo "$id|$state|$code:0|00:00:01|node01|"
+        echo "$id.batch|$state|$code:0|00:00:01|node01|1024K"
+    done < "$exit_codes"
+done
+"#;
+
+/// Starts the rest of its args, as a job step on one node would.
+const SRUN: &str = r#"while [[ $1 == -* ]]; do
+    shift
+done
+exec "$@"
+"#;
+
+/// Fails each container run with the argument "fail". No images are stored locally.
+const PODMAN: &str = r#"case "$1" in
+    run)
+        echo "podman $*"
+        for arg; do
+            if [[ $arg == fail ]]; then
+                echo "task failed" >&2
+                exit 1
+            fi
+        done
+        ;;
+    image) exit 125 ;;
+esac
+"#;
+
+const SKOPEO: &str = r#"echo "sha256:$(printf '0%.0s' {1..64})"
+"#;
+
+const SINFO: &str = r#"if [[ " $* " == *" --format=%G "* ]]; then
+    printf 'gpu:a100:4(S:0-1)\ngpu:a40:2\n(null)\n'
+else
+    echo "node01 idle"
+fi
+"#;
+
+pub struct FakeCluster {
+    pub dir: tempfile::TempDir,
+}
+
+/// A recorded call of a fake executable.
+pub struct Call {
+    pub args: Vec<String>,
+    /// What was written to sbatch's stdin - the batch script
+    pub stdin: String,
+}
+
+/// The outcome of running one of the binaries.
+pub struct Run {
+    pub status: ExitStatus,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl FakeCluster {
+    pub fn new() -> FakeCluster {
+        let cluster = FakeCluster {
+            dir: tempfile::tempdir().unwrap(),
+        };
+        for dir in ["bin", "calls", "jobs", "home"] {
+            std::fs::create_dir(cluster.path(dir)).unwrap();
+        }
+        let tools = [
+            ("sbatch", SBATCH),
+            ("srun", SRUN),
+            ("squeue", ""),
+            ("sacct", SACCT),
+            ("scontrol", SCONTROL),
+            ("scancel", ""),
+            ("sinfo", SINFO),
+            ("skopeo", SKOPEO),
+            ("podman", PODMAN),
+        ];
+        for (tool, script) in tools {
+            cluster.write_executable(tool, &(RECORD_CALL.replace("TOOL", tool) + script));
+        }
+        cluster.write_executable("fake-run-job", RUN_JOB);
+        // The built-in profile, without the site-wide catalog of whatever machine runs the tests
+        let catalog = cluster.path("site-images.toml");
+        cluster.write("site.toml", &format!("catalog = {catalog:?}\n"));
+        cluster
+    }
+
+    pub fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    pub fn home(&self) -> PathBuf {
+        self.path("home")
+    }
+
+    /// Writes a file in the cluster's directory, which the binaries run in.
+    pub fn write(&self, name: &str, contents: &str) -> PathBuf {
+        let path = self.path(name);
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    fn write_executable(&self, name: &str, script: &str) {
+        use std::os::unix::fs::PermissionsExt;
+        let path = self.path("bin").join(name);
+        std::fs::write(&path, script).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// Makes every following sbatch call fail.
+    pub fn fail_sbatch(&self) {
+        self.write("sbatch_fails", "");
+    }
+
+    /// Makes every following "scontrol release" fail.
+    pub fn fail_release(&self) {
+        self.write("release_fails", "");
+    }
+
+    /// Runs a binary of this crate, e.g. env!("CARGO_BIN_EXE_ihn-hpc-sbatch-array"), on this
+    /// cluster.
+    pub fn run(&self, binary: &str, args: &[&str]) -> Run {
+        let path = std::env::join_paths(std::iter::once(self.path("bin")).chain(
+            std::env::split_paths(&std::env::var_os("PATH").unwrap_or_default()),
+        ))
+        .unwrap();
+        let mut command = Command::new(binary);
+        command
+            .args(args)
+            .current_dir(self.dir.path())
+            .env("PATH", path)
+            .env("HOME", self.home())
+            .env("USER", "tester")
+            .env("IHN_HPC_SITE", self.path("site.toml"))
+            .env("FAKE_CLUSTER", self.dir.path());
+        for (name, _) in std::env::vars_os() {
+            if name.to_string_lossy().starts_with("SLURM_") {
+                command.env_remove(name);
+            }
+        }
+        let output = command.output().unwrap();
+        Run {
+            status: output.status,
+            stdout: String::from_utf8_lossy(&output.stdout).to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).to_string(),
+        }
+    }
+
+    /// The calls of `tool` so far, in order.
+    pub fn calls(&self, tool: &str) -> Vec<Call> {
+        let mut dirs = std::fs::read_dir(self.path("calls"))
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|dir| {
+                let name = dir.file_name().unwrap().to_string_lossy().to_string();
+                name.split('-').nth(1) == Some(tool)
+            })
+            .collect::<Vec<_>>();
+        dirs.sort();
+        dirs.iter()
+            .map(|dir| Call {
+                args: read_argv(&dir.join("argv")),
+                stdin: std::fs::read_to_string(dir.join("stdin")).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// The args of each "podman run" so far - one per task that has run.
+    pub fn podman_runs(&self) -> Vec<Vec<String>> {
+        self.calls("podman")
+            .into_iter()
+            .filter(|call| call.args.first().is_some_and(|arg| arg == "run"))
+            .map(|call| call.args)
+            .collect()
+    }
+}
+
+fn read_argv(path: &Path) -> Vec<String> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .split_terminator('\0')
+        .map(str::to_string)
+        .collect()
+}
+
+/// `args` as owned strings, for comparing with recorded calls.
+pub fn strings(args: &[&str]) -> Vec<String> {
+    args.iter().map(|arg| arg.to_string()).collect()
+}
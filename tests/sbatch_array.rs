@@ -0,0 +1,633 @@
+//! ihn-hpc-sbatch-array against a fake Slurm cluster.
+
+mod common;
+
+use common::{FakeCluster, strings};
+
+const SBATCH_ARRAY: &str = env!("CARGO_BIN_EXE_ihn-hpc-sbatch-array");
+
+const PINNED_FREESURFER: &str = "docker.io/freesurfer/freesurfer@sha256:0000000000000000000000000000000000000000000000000000000000000000";
+
+#[test]
+fn submits_freesurfer_array() {
+    let cluster = FakeCluster::new();
+    cluster.write("args.txt", "sub-01\nsub-02\n");
+    let script = cluster.write("recon.sh", "#!/bin/bash\nrecon-all -all -s \"$1\"\n");
+    let script = std::fs::canonicalize(script).unwrap().display().to_string();
+    let run = cluster.run(SBATCH_ARRAY, &["freesurfer", "recon.sh", "args.txt"]);
+    assert!(run.status.success(), "{}", run.stderr);
+    let home = cluster.home().display().to_string();
+    let log_dir = format!("{home}/hpc-logs/recon");
+    assert_eq!(
+        format!("Submitted batch job 1001\nLogs are in {log_dir}/1001\n"),
+        run.stdout
+    );
+
+    let sbatch = cluster.calls("sbatch");
+    assert_eq!(1, sbatch.len());
+    assert_eq!(
+        strings(&[
+            "--parsable",
+            "--array=0-1%16",
+            "--hold",
+            &format!("--output={log_dir}/%A/%A_%a.out"),
+            &format!("--error={log_dir}/%A/%A_%a.err"),
+        ]),
+        sbatch[0].args
+    );
+    assert_eq!(
+        format!(
+            r#"#!/bin/bash
+set -u
+export TMPDIR="/ssd/home/$USER/TEMP"
+ARG_COUNT=1
+COMMAND_ARGS=(
+sub-01
+sub-02
+)
+TASK_ARGS=("${{COMMAND_ARGS[@]:SLURM_ARRAY_TASK_ID*ARG_COUNT:ARG_COUNT}}")
+srun --ntasks=1 podman run --rm  -v "$HOME":"$HOME" -e HPC_HOME="$HOME"  -v /mnt/home/shared/:/mnt/home/shared/ -v {script}:{script} -v /mnt/apps/etc/fs_license.txt:/usr/local/freesurfer/.license:ro -v /opt/matlab/runtime/R2019b/v97/:/usr/local/freesurfer/MCRv97 -e FS_LICENSE=/usr/local/freesurfer/.license --authfile /mnt/apps/etc/auth.json --entrypoint {script}  {PINNED_FREESURFER} "${{TASK_ARGS[@]}}"
+"#
+        ),
+        sbatch[0].stdin
+    );
+    assert_eq!(
+        strings(&["release", "1001"]),
+        cluster.calls("scontrol").last().unwrap().args
+    );
+
+    let podman_runs = cluster.podman_runs();
+    assert_eq!(2, podman_runs.len());
+    assert_eq!(
+        strings(&[
+            "run",
+            "--rm",
+            "-v",
+            &format!("{home}:{home}"),
+            "-e",
+            &format!("HPC_HOME={home}"),
+            "-v",
+            "/mnt/home/shared/:/mnt/home/shared/",
+            "-v",
+            &format!("{script}:{script}"),
+            "-v",
+            "/mnt/apps/etc/fs_license.txt:/usr/local/freesurfer/.license:ro",
+            "-v",
+            "/opt/matlab/runtime/R2019b/v97/:/usr/local/freesurfer/MCRv97",
+            "-e",
+            "FS_LICENSE=/usr/local/freesurfer/.license",
+            "--authfile",
+            "/mnt/apps/etc/auth.json",
+            "--entrypoint",
+            &script,
+            PINNED_FREESURFER,
+            "sub-01",
+        ]),
+        podman_runs[0]
+    );
+    assert_eq!("sub-02", podman_runs[1].last().unwrap());
+
+    let logs = cluster.home().join("hpc-logs/recon/1001");
+    let output = std::fs::read_to_string(logs.join("sub-02.out")).unwrap();
+    assert!(output.starts_with("podman run --rm"), "{output}");
+    assert_eq!(
+        "TASK\tOUTPUT\tERROR\tARGUMENTS\n\
+         1001_0\t1001_0.out\t1001_0.err\tsub-01\n\
+         1001_1\t1001_1.out\t1001_1.err\tsub-02\n",
+        std::fs::read_to_string(logs.join("index.tsv")).unwrap()
+    );
+    assert!(
+        cluster
+            .home()
+            .join(".local/share/ihn-hpc/jobs/1001.json")
+            .exists()
+    );
+}
+
+#[test]
+fn passes_columns_as_arguments_and_environment() {
+    let cluster = FakeCluster::new();
+    cluster.write(
+        "subjects.csv",
+        "SUBJECT,SESSION\nsub-01,1\n\"sub 02\",\"it's $HOME\"\n",
+    );
+    let run = cluster.run(
+        SBATCH_ARRAY,
+        &[
+            "--no-pin",
+            "--delimiter=comma",
+            "--header",
+            "--podman-args=--cpus=2 --env='GREETING=hello world'",
+            "ubuntu",
+            "echo",
+            "subjects.csv",
+        ],
+    );
+    assert!(run.status.success(), "{}", run.stderr);
+    let podman_runs = cluster.podman_runs();
+    assert_eq!(2, podman_runs.len());
+    let home = cluster.home().display().to_string();
+    assert_eq!(
+        strings(&[
+            "run",
+            "--rm",
+            "-v",
+            &format!("{home}:{home}"),
+            "-e",
+            &format!("HPC_HOME={home}"),
+            "-e",
+            "SUBJECT=sub 02",
+            "-e",
+            "SESSION=it's $HOME",
+            "-v",
+            "/mnt/home/shared/:/mnt/home/shared/",
+            "--authfile",
+            "/mnt/apps/etc/auth.json",
+            "--entrypoint",
+            "echo",
+            "--cpus=2",
+            "--env=GREETING=hello world",
+            "ubuntu",
+            "sub 02",
+            "it's $HOME",
+        ]),
+        podman_runs[1]
+    );
+}
+
+#[test]
+fn fills_in_command_templates() {
+    let cluster = FakeCluster::new();
+    cluster.write(
+        "scans.csv",
+        "SUBJECT,SCAN\nsub-01,/data/t1.nii.gz\n\"sub 02\",\"/data/it's $HOME.nii\"\nsub-03,t2.nii\nfail,x.nii\n",
+    );
+    let run = cluster.run(
+        SBATCH_ARRAY,
+        &[
+            "--no-pin",
+            "--delimiter=comma",
+            "--header",
+            "--max-array-size=2",
+            "ubuntu",
+            "recon-all -s {SUBJECT} -i {2} -o '/out/{1}/{2/.}' {#}:{%} {}",
+            "scans.csv",
+        ],
+    );
+    assert!(run.status.success(), "{}", run.stderr);
+    let podman_runs = cluster.podman_runs();
+    assert_eq!(4, podman_runs.len());
+    let args = |run: &Vec<String>| {
+        let entrypoint = run.iter().position(|arg| arg == "--entrypoint").unwrap();
+        assert_eq!("recon-all", run[entrypoint + 1]);
+        run[entrypoint + 3..].to_vec()
+    };
+    assert_eq!(
+        strings(&[
+            "-s",
+            "sub 02",
+            "-i",
+            "/data/it's $HOME.nii",
+            "-o",
+            "/out/sub 02/it's $HOME",
+            "2:1",
+            "sub 02",
+            "/data/it's $HOME.nii",
+        ]),
+        args(&podman_runs[1])
+    );
+    assert_eq!(
+        strings(&[
+            "-s",
+            "sub-03",
+            "-i",
+            "t2.nii",
+            "-o",
+            "/out/sub-03/t2",
+            "3:0",
+            "sub-03",
+            "t2.nii",
+        ]),
+        args(&podman_runs[2])
+    );
+
+    // Retried tasks keep the number of their line
+    let run = cluster.run(SBATCH_ARRAY, &["retry", "1001"]);
+    assert!(run.status.success(), "{}", run.stderr);
+    assert_eq!(
+        strings(&[
+            "-s",
+            "fail",
+            "-i",
+            "x.nii",
+            "-o",
+            "/out/fail/x",
+            "4:0",
+            "fail",
+            "x.nii",
+        ]),
+        args(&cluster.podman_runs()[4])
+    );
+
+    let run = cluster.run(
+        SBATCH_ARRAY,
+        &["--no-pin", "ubuntu", "echo {2}", "scans.csv"],
+    );
+    assert_eq!(
+        "ERROR: {2} in COMMAND refers to argument 2, but each task has 1\n",
+        run.stderr
+    );
+    assert_eq!(3, cluster.calls("sbatch").len());
+}
+
+#[test]
+fn chains_arrays_larger_than_the_maximum_array_size() {
+    let cluster = FakeCluster::new();
+    cluster.write("args.txt", "a\nb\nc\n");
+    let run = cluster.run(
+        SBATCH_ARRAY,
+        &[
+            "--no-pin",
+            "--max-array-size=2",
+            "--max-tasks=4",
+            "ubuntu",
+            "echo",
+            "args.txt",
+        ],
+    );
+    assert!(run.status.success(), "{}", run.stderr);
+    let log_dir = cluster.home().join("hpc-logs/echo");
+    assert_eq!(
+        format!(
+            "Submitted batch job 1001 (arguments 1-2)\n\
+             Submitted batch job 1002 (arguments 3-3)\n\
+             Logs are in {}\n",
+            log_dir.join("1001").display()
+        ),
+        run.stdout
+    );
+    let sbatch = cluster.calls("sbatch");
+    assert_eq!(2, sbatch.len());
+    assert_eq!(
+        strings(&[
+            "--parsable",
+            "--array=0-0%4",
+            "--dependency=afterany:1001",
+            &format!("--output={}/1001/%A_%a.out", log_dir.display()),
+            &format!("--error={}/1001/%A_%a.err", log_dir.display()),
+        ]),
+        sbatch[1].args
+    );
+    let args = cluster
+        .podman_runs()
+        .iter()
+        .map(|run| run.last().unwrap().clone())
+        .collect::<Vec<_>>();
+    assert_eq!(strings(&["a", "b", "c"]), args);
+    assert!(log_dir.join("1001/c.out").exists());
+}
+
+#[test]
+fn starts_after_earlier_submissions() {
+    let cluster = FakeCluster::new();
+    cluster.write("args.txt", "a\nb\nc\n");
+    let submit = |after: &[&str]| {
+        let mut args = after.to_vec();
+        args.extend([
+            "--no-pin",
+            "--max-array-size=2",
+            "ubuntu",
+            "echo",
+            "args.txt",
+        ]);
+        let run = cluster.run(SBATCH_ARRAY, &args);
+        assert!(run.status.success(), "{}", run.stderr);
+    };
+    submit(&[]);
+    submit(&["--after-corr=1001"]);
+    submit(&[
+        "--after-ok=1002",
+        "--after-any=999",
+        "--dependency=singleton",
+    ]);
+    let dependencies = cluster
+        .calls("sbatch")
+        .iter()
+        .map(|call| {
+            call.args
+                .iter()
+                .find_map(|arg| arg.strip_prefix("--dependency="))
+                .unwrap_or_default()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(
+        strings(&[
+            "",
+            "afterany:1001",
+            "aftercorr:1001",
+            "afterany:1003,aftercorr:1002",
+            "singleton,afterok:1001:1002,afterany:999",
+            "afterany:1005,singleton,afterok:1001:1002,afterany:999",
+        ]),
+        dependencies
+    );
+
+    let run = cluster.run(
+        SBATCH_ARRAY,
+        &[
+            "--after-corr=1001",
+            "--no-pin",
+            "--max-array-size=1",
+            "ubuntu",
+            "echo",
+            "args.txt",
+        ],
+    );
+    assert!(!run.status.success());
+    assert!(
+        run.stderr
+            .contains("split into job arrays at other arguments"),
+        "{}",
+        run.stderr
+    );
+    assert_eq!(6, cluster.calls("sbatch").len());
+}
+
+#[test]
+fn submits_pipelines() {
+    let cluster = FakeCluster::new();
+    cluster.write("args.txt", "a\nb\nc\n");
+    cluster.write(
+        "pipeline.toml",
+        r#"args = "args.txt"
+
+[stages.align]
+image = "ubuntu"
+command = "echo"
+
+[stages.extract]
+image = "ubuntu"
+command = "echo"
+after = ["align"]
+
+[stages.report]
+image = "ubuntu"
+command = "echo"
+resources = { time = "1:00:00" }
+after = ["extract"]
+after_all = ["align"]
+"#,
+    );
+    let run = cluster.run(
+        SBATCH_ARRAY,
+        &[
+            "pipeline",
+            "run",
+            "--no-pin",
+            "--max-array-size=2",
+            "pipeline.toml",
+        ],
+    );
+    assert!(run.status.success(), "{}", run.stderr);
+    let sbatch = cluster.calls("sbatch");
+    let option = |call: &common::Call, name: &str| {
+        call.args
+            .iter()
+            .find_map(|arg| arg.strip_prefix(name))
+            .unwrap_or_default()
+            .to_string()
+    };
+    assert_eq!(
+        strings(&["align", "align", "extract", "extract", "report", "report"]),
+        sbatch
+            .iter()
+            .map(|call| option(call, "--job-name="))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        strings(&[
+            "",
+            "afterany:1001",
+            "aftercorr:1001",
+            "afterany:1003,aftercorr:1002",
+            "aftercorr:1003,afterok:1001:1002",
+            "afterany:1005,aftercorr:1004,afterok:1001:1002",
+        ]),
+        sbatch
+            .iter()
+            .map(|call| option(call, "--dependency="))
+            .collect::<Vec<_>>()
+    );
+    assert_eq!("1:00:00", option(&sbatch[4], "--time="));
+    assert_eq!(9, cluster.podman_runs().len());
+    assert!(
+        cluster
+            .home()
+            .join(".local/share/ihn-hpc/jobs/pipeline-1001.json")
+            .exists()
+    );
+
+    // Each stage can be followed up on by any of its job IDs
+    let run = cluster.run(SBATCH_ARRAY, &["status", "1006"]);
+    assert!(run.status.success(), "{}", run.stderr);
+    assert!(run.stdout.contains("1006_0  c "), "{}", run.stdout);
+
+    cluster.write(
+        "cycle.toml",
+        "args = \"args.txt\"\n\
+         [stages.a]\nimage = \"ubuntu\"\ncommand = \"echo\"\nafter = [\"b\"]\n\
+         [stages.b]\nimage = \"ubuntu\"\ncommand = \"echo\"\nafter_all = [\"a\"]\n",
+    );
+    let run = cluster.run(SBATCH_ARRAY, &["pipeline", "run", "--no-pin", "cycle.toml"]);
+    assert_eq!("ERROR: Stages a, b come after each other\n", run.stderr);
+    assert_eq!(6, cluster.calls("sbatch").len());
+}
+
+#[test]
+fn retries_failed_tasks() {
+    let cluster = FakeCluster::new();
+    cluster.write("args.txt", "a\nfail\nc\n");
+    let run = cluster.run(
+        SBATCH_ARRAY,
+        &[
+            "--no-pin",
+            "--dependency=afterok:999",
+            "ubuntu",
+            "echo",
+            "args.txt",
+        ],
+    );
+    assert!(run.status.success(), "{}", run.stderr);
+
+    let run = cluster.run(SBATCH_ARRAY, &["status", "1001"]);
+    assert!(run.status.success(), "{}", run.stderr);
+    assert!(
+        run.stdout.ends_with("COMPLETED: 2\nFAILED: 1\nTOTAL: 3\n"),
+        "{}",
+        run.stdout
+    );
+
+    let run = cluster.run(SBATCH_ARRAY, &["retry", "1001"]);
+    assert!(run.status.success(), "{}", run.stderr);
+    assert!(
+        run.stderr.contains("Retrying 1 of 3 tasks"),
+        "{}",
+        run.stderr
+    );
+    let sbatch = cluster.calls("sbatch");
+    assert_eq!(2, sbatch.len());
+    assert_eq!("--array=0-0%16", sbatch[1].args[1]);
+    assert!(
+        sbatch[0]
+            .args
+            .contains(&"--dependency=afterok:999".to_string())
+    );
+    assert!(
+        !sbatch[1]
+            .args
+            .iter()
+            .any(|arg| arg.starts_with("--dependency"))
+    );
+    assert!(
+        run.stderr
+            .contains("WARN: --dependency=afterok:999 of job 1001 is not retried"),
+        "{}",
+        run.stderr
+    );
+    assert!(sbatch[1].stdin.contains("COMMAND_ARGS=(\nfail\n)"));
+    assert_eq!("fail", cluster.podman_runs()[3].last().unwrap());
+}
+
+#[test]
+fn dry_runs_submit_nothing() {
+    let cluster = FakeCluster::new();
+    cluster.write("args.txt", "a\n");
+    let run = cluster.run(
+        SBATCH_ARRAY,
+        &["--dry-run", "--no-pin", "ubuntu", "echo", "args.txt"],
+    );
+    assert!(run.status.success(), "{}", run.stderr);
+    assert!(
+        run.stdout.starts_with("image: ubuntu\nlogs: "),
+        "{}",
+        run.stdout
+    );
+    assert!(
+        run.stdout
+            .contains("\nsbatch --parsable --array=0-0%16 --hold ")
+    );
+    assert!(cluster.calls("sbatch").is_empty());
+    assert!(!cluster.home().join(".local/share/ihn-hpc").exists());
+}
+
+#[test]
+fn runs_tasks_locally() {
+    let cluster = FakeCluster::new();
+    cluster.write("args.txt", "a\nfail\n");
+    let run = cluster.run(
+        SBATCH_ARRAY,
+        &[
+            "--backend=local",
+            "--no-pin",
+            "--log-dir=logs",
+            "ubuntu",
+            "echo",
+            "args.txt",
+        ],
+    );
+    assert!(!run.status.success());
+    assert!(
+        run.stdout.ends_with("1 of 2 tasks failed\n"),
+        "{}",
+        run.stdout
+    );
+    assert!(cluster.calls("sbatch").is_empty());
+    assert!(cluster.calls("srun").is_empty());
+    let mut args = cluster
+        .podman_runs()
+        .iter()
+        .map(|run| run.last().unwrap().clone())
+        .collect::<Vec<_>>();
+    args.sort();
+    assert_eq!(strings(&["a", "fail"]), args);
+    // Tasks run the preamble of batch scripts, e.g. the site's TMPDIR
+    let dir = std::fs::read_dir(cluster.path("logs"))
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap()
+        .path();
+    let script = std::fs::read_to_string(dir.join("task.sh")).unwrap();
+    assert!(
+        script.starts_with("#!/bin/bash\nset -u\nexport TMPDIR=\"/ssd/home/$USER/TEMP\"\n"),
+        "{script}"
+    );
+}
+
+#[test]
+fn reports_errors() {
+    let cluster = FakeCluster::new();
+    cluster.write("args.txt", "a\n");
+
+    let run = cluster.run(SBATCH_ARRAY, &[]);
+    assert!(!run.status.success());
+    assert!(
+        run.stderr
+            .starts_with("error: the following required arguments were not provided"),
+        "{}",
+        run.stderr
+    );
+
+    let run = cluster.run(SBATCH_ARRAY, &["--no-pin", "ubuntu", "echo", "missing.txt"]);
+    assert!(!run.status.success());
+    assert!(run.stderr.starts_with("ERROR: "), "{}", run.stderr);
+    assert!(run.stderr.contains("missing.txt"), "{}", run.stderr);
+
+    let run = cluster.run(
+        SBATCH_ARRAY,
+        &[
+            "--no-pin",
+            "--sbatch-args=--array=0-9",
+            "ubuntu",
+            "echo",
+            "args.txt",
+        ],
+    );
+    assert!(!run.status.success());
+    assert!(
+        run.stderr
+            .starts_with("ERROR: \"--array=0-9\" in --sbatch-args would override"),
+        "{}",
+        run.stderr
+    );
+    assert!(cluster.calls("sbatch").is_empty());
+
+    cluster.fail_release();
+    let run = cluster.run(SBATCH_ARRAY, &["--no-pin", "ubuntu", "echo", "args.txt"]);
+    assert!(!run.status.success());
+    assert!(
+        run.stderr
+            .starts_with("ERROR: Unable to release job 1001, cancelled it: scontrol release 1001"),
+        "{}",
+        run.stderr
+    );
+    assert_eq!(strings(&["1001"]), cluster.calls("scancel")[0].args);
+    assert!(!cluster.home().join("hpc-logs/echo/1001").exists());
+
+    cluster.fail_sbatch();
+    let run = cluster.run(SBATCH_ARRAY, &["--no-pin", "ubuntu", "echo", "args.txt"]);
+    assert!(!run.status.success());
+    assert!(
+        run.stderr
+            .contains("sbatch: error: Batch job submission failed"),
+        "{}",
+        run.stderr
+    );
+    assert!(
+        run.stderr.ends_with("ERROR: Something went wrong...\n"),
+        "{}",
+        run.stderr
+    );
+    assert!(!cluster.home().join(".local/share/ihn-hpc").exists());
+}
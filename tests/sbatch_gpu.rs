@@ -0,0 +1,191 @@
+//! ihn-hpc-sbatch-gpu against a fake Slurm cluster.
+
+mod common;
+
+use common::{FakeCluster, strings};
+
+const SBATCH_GPU: &str = env!("CARGO_BIN_EXE_ihn-hpc-sbatch-gpu");
+const SBATCH_ARRAY: &str = env!("CARGO_BIN_EXE_ihn-hpc-sbatch-array");
+
+#[test]
+fn submits_gpu_job() {
+    let cluster = FakeCluster::new();
+    let run = cluster.run(
+        SBATCH_GPU,
+        &[
+            "--gpus=a100:2",
+            "--no-pin",
+            "--time=1:00:00",
+            "ubuntu",
+            "nvidia-smi",
+            "-L",
+        ],
+    );
+    assert!(run.status.success(), "{}", run.stderr);
+    assert_eq!("Submitted batch job 1001\n", run.stdout);
+    let sbatch = cluster.calls("sbatch");
+    assert_eq!(1, sbatch.len());
+    assert_eq!(
+        strings(&["--parsable", "--gres=gpu:a100:2", "--time=1:00:00"]),
+        sbatch[0].args
+    );
+    assert_eq!(
+        r#"#!/bin/bash
+set -u
+export TMPDIR="/ssd/home/$USER/TEMP"
+GPU_ARGS=(--device=nvidia.com/gpu=all)
+if [[ -n ${SLURM_JOB_GPUS:-} ]]; then
+    GPU_ARGS=()
+    VISIBLE_DEVICES=()
+    for gpu in ${SLURM_JOB_GPUS//,/ }; do
+        GPU_ARGS+=(--device=nvidia.com/gpu="$gpu")
+        VISIBLE_DEVICES+=(${#VISIBLE_DEVICES[@]})
+    done
+    GPU_ARGS+=(-e CUDA_VISIBLE_DEVICES="$(IFS=,; echo "${VISIBLE_DEVICES[*]}")")
+fi
+srun --ntasks=1 podman run --rm --security-opt=label=disable "${GPU_ARGS[@]}" -v "$HOME":"$HOME" -e HPC_HOME="$HOME"  -v /mnt/home/shared/:/mnt/home/shared/   --authfile /mnt/apps/etc/auth.json --entrypoint nvidia-smi  ubuntu -L
+"#,
+        sbatch[0].stdin
+    );
+
+    // The fake cluster allocates GPUs 0 and 1
+    let home = cluster.home().display().to_string();
+    assert_eq!(
+        vec![strings(&[
+            "run",
+            "--rm",
+            "--security-opt=label=disable",
+            "--device=nvidia.com/gpu=0",
+            "--device=nvidia.com/gpu=1",
+            "-e",
+            "CUDA_VISIBLE_DEVICES=0,1",
+            "-v",
+            &format!("{home}:{home}"),
+            "-e",
+            &format!("HPC_HOME={home}"),
+            "-v",
+            "/mnt/home/shared/:/mnt/home/shared/",
+            "--authfile",
+            "/mnt/apps/etc/auth.json",
+            "--entrypoint",
+            "nvidia-smi",
+            "ubuntu",
+            "-L",
+        ])],
+        cluster.podman_runs()
+    );
+}
+
+#[test]
+fn records_single_gpu_jobs() {
+    let cluster = FakeCluster::new();
+    let run = cluster.run(
+        SBATCH_GPU,
+        &["--no-pin", "ubuntu", "train", "--epochs", "fail"],
+    );
+    assert!(run.status.success(), "{}", run.stderr);
+    assert!(
+        cluster
+            .home()
+            .join(".local/share/ihn-hpc/jobs/1001.json")
+            .exists()
+    );
+
+    let run = cluster.run(SBATCH_ARRAY, &["status", "1001"]);
+    assert!(run.status.success(), "{}", run.stderr);
+    assert!(
+        run.stdout
+            .lines()
+            .any(|line| line.starts_with("1001_0  --epochs fail  FAILED")),
+        "{}",
+        run.stdout
+    );
+}
+
+#[test]
+fn submits_gpu_arrays() {
+    let cluster = FakeCluster::new();
+    cluster.write("args.txt", "a\nb\n");
+    let run = cluster.run(
+        SBATCH_GPU,
+        &[
+            "--gpus=any:1",
+            "--no-pin",
+            "--arg-file=args.txt",
+            "--max-tasks=1",
+            "ubuntu",
+            "train",
+        ],
+    );
+    assert!(run.status.success(), "{}", run.stderr);
+    let sbatch = cluster.calls("sbatch");
+    assert_eq!(
+        strings(&["--parsable", "--array=0-1%1", "--gres=gpu:1", "--hold"]),
+        sbatch[0].args[..4]
+    );
+    let podman_runs = cluster.podman_runs();
+    assert_eq!(2, podman_runs.len());
+    for (run, arg) in podman_runs.iter().zip(["a", "b"]) {
+        assert_eq!(
+            strings(&["--device=nvidia.com/gpu=0", "-e", "CUDA_VISIBLE_DEVICES=0"]),
+            run[3..6]
+        );
+        assert_eq!(arg, run.last().unwrap());
+    }
+}
+
+#[test]
+fn rejects_unavailable_gpus() {
+    let cluster = FakeCluster::new();
+    let run = cluster.run(
+        SBATCH_GPU,
+        &["--gpus=h100:1", "--no-pin", "ubuntu", "nvidia-smi"],
+    );
+    assert!(!run.status.success());
+    assert_eq!(
+        "ERROR: No node has 1 GPUs of model h100 - the cluster advertises gpu:a100:4, gpu:a40:2\n",
+        run.stderr
+    );
+
+    let run = cluster.run(
+        SBATCH_GPU,
+        &[
+            "--sbatch-arg=--gres=gpu:2",
+            "--no-pin",
+            "ubuntu",
+            "nvidia-smi",
+        ],
+    );
+    assert!(!run.status.success());
+    assert!(
+        run.stderr
+            .starts_with("ERROR: \"--gres=gpu:2\" in --sbatch-args would override the --gres"),
+        "{}",
+        run.stderr
+    );
+    assert!(cluster.calls("sbatch").is_empty());
+}
+
+#[test]
+fn rejects_raw_dependency_of_single_jobs() {
+    let cluster = FakeCluster::new();
+    let run = cluster.run(
+        SBATCH_GPU,
+        &[
+            "--after-ok=999",
+            "--sbatch-args=--dependency=afterany:998",
+            "--no-pin",
+            "ubuntu",
+            "nvidia-smi",
+        ],
+    );
+    assert!(!run.status.success());
+    assert!(
+        run.stderr.starts_with(
+            "ERROR: \"--dependency=afterany:998\" in --sbatch-args would override the --dependency"
+        ),
+        "{}",
+        run.stderr
+    );
+    assert!(cluster.calls("sbatch").is_empty());
+}